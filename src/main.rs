@@ -5,7 +5,9 @@
     clippy::needless_pass_by_value,
     clippy::multiple_crate_versions,
     clippy::cast_possible_wrap,
-    clippy::cast_precision_loss
+    clippy::cast_precision_loss,
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss
 )]
 #![allow(dead_code, unused)]
 
@@ -14,18 +16,29 @@ use bevy::prelude::*;
 mod systems;
 
 use bevy::core_pipeline::clear_color::ClearColorConfig;
-use systems::dynamic_textures::{AddDynamicTextureEvent, RenderToTextureDescriptor, StartColor};
+use systems::animation::{self, Easing};
+use systems::audio::run_analyze_audio_command;
+use systems::bench::BenchPlugin;
+use systems::color_generator::{ColorOptions, MinContrast};
+use systems::color_vision::{validate_descriptors, ColorVisionPlugin};
+use systems::dynamic_textures::{
+    AddDynamicTextureEvent, GeneratorParams, RenderToTextureDescriptor, StartColor,
+};
 use systems::dynamic_textures::{DynamicTextures, DynamicTexturesPlugin};
+use systems::morph::{MorphMatching, MorphTextureEvent};
+use systems::palette_extraction::run_extract_palette_command;
+use systems::reactions::{ReactionKind, TextureReact};
 
 //-----------------------
 
+// hues are fractions of a turn: red at 0.0, green at a third
 const RED_MONSTER_START_COLOR: StartColor = StartColor {
-    hue: 0.1,
+    hue: 0.0,
     saturation: 0.8,
     lightness: 0.7,
 };
 const GREEN_MONSTER_START_COLOR: StartColor = StartColor {
-    hue: 0.4,
+    hue: 0.33,
     saturation: 0.8,
     lightness: 0.6,
 };
@@ -36,6 +49,18 @@ const RED_MONSTER_DESCRIPTOR: RenderToTextureDescriptor = RenderToTextureDescrip
     size: 256,
     start_color: RED_MONSTER_START_COLOR,
     background_color: Color::MAROON,
+    // keeps the circles from disappearing into the maroon
+    color: ColorOptions {
+        min_contrast: Some(MinContrast::Wcag(1.5)),
+        ..ColorOptions::DEFAULT
+    },
+    params: GeneratorParams::Default,
+    layers: &[],
+    inputs: &[],
+    filters: &[],
+    animation: animation::CLASSIC,
+    formulas: None,
+    reveal: None,
 };
 
 const GREEN_MONSTER_DESCRIPTOR: RenderToTextureDescriptor = RenderToTextureDescriptor {
@@ -44,6 +69,14 @@ const GREEN_MONSTER_DESCRIPTOR: RenderToTextureDescriptor = RenderToTextureDescr
     size: 512,
     start_color: GREEN_MONSTER_START_COLOR,
     background_color: Color::LIME_GREEN,
+    color: ColorOptions::DEFAULT,
+    params: GeneratorParams::Default,
+    layers: &[],
+    inputs: &[],
+    filters: &[],
+    animation: animation::CLASSIC,
+    formulas: None,
+    reveal: None,
 };
 
 //------------------------------------------------------------
@@ -55,6 +88,22 @@ enum Direction {
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("extract-palette") {
+        if let Err(e) = run_extract_palette_command(&args[1..]) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("analyze-audio") {
+        if let Err(e) = run_analyze_audio_command(&args[1..]) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let mut app = App::new();
     app.add_plugins(DefaultPlugins)
         .insert_resource(WindowDescriptor {
@@ -66,12 +115,29 @@ fn main() {
         .insert_resource(Msaa { samples: 4 })
         .insert_resource(ClearColor(Color::BLACK));
 
-    app.add_plugin(DynamicTexturesPlugin);
+    app.add_plugin(DynamicTexturesPlugin)
+        .add_plugin(ColorVisionPlugin);
 
-    app.add_system(draw_textured_rect_setup)
-        .add_system(move_textured_rect);
+    if args.first().map(String::as_str) == Some("bench") {
+        // animates the 512px monster on its own, with its shared circle mesh and then a mesh per
+        // circle per frame, and logs frame times and mesh counts for both
+        app.add_plugin(BenchPlugin {
+            descriptor: GREEN_MONSTER_DESCRIPTOR,
+            warmup_frames: 60,
+            frames: 600,
+        });
+    } else {
+        app.add_system(draw_textured_rect_setup)
+            .add_system(move_textured_rect)
+            .add_system(evolve_red_monster);
+        // debug keys; in release builds only gameplay sends reactions
+        if cfg!(debug_assertions) {
+            app.add_system(hurt_red_monster);
+        }
+    }
 
-    app.add_startup_system(add_game_camera);
+    app.add_startup_system(add_game_camera)
+        .add_startup_system(check_monster_colors);
 
     app.add_system(bevy::window::close_on_esc).run();
 }
@@ -121,6 +187,45 @@ fn move_textured_rect(
         }
     }
 }
+// E turns the red monster into a green one
+fn evolve_red_monster(keys: Res<Input<KeyCode>>, mut ew: EventWriter<MorphTextureEvent>) {
+    if keys.just_pressed(KeyCode::E) {
+        ew.send(MorphTextureEvent {
+            name: RED_MONSTER_DESCRIPTOR.name.to_string(),
+            to: GREEN_MONSTER_DESCRIPTOR,
+            matching: MorphMatching::Nearest,
+            duration: 2.0,
+            easing: Easing::EaseOutCubic,
+        });
+    }
+}
+
+// H hits the red monster, J heals it and R enrages it
+fn hurt_red_monster(keys: Res<Input<KeyCode>>, mut ew: EventWriter<TextureReact>) {
+    for (key, kind) in [
+        (KeyCode::H, ReactionKind::Hit),
+        (KeyCode::J, ReactionKind::Heal),
+        (KeyCode::R, ReactionKind::Enrage),
+    ] {
+        if keys.just_pressed(key) {
+            ew.send(TextureReact {
+                name: RED_MONSTER_DESCRIPTOR.name.to_string(),
+                kind,
+                intensity: 1.0,
+            });
+        }
+    }
+}
+
+// the monsters are told apart by color, so they have to stay apart for colorblind players too
+fn check_monster_colors() {
+    let monsters = [RED_MONSTER_DESCRIPTOR, GREEN_MONSTER_DESCRIPTOR];
+    // neither draws from a palette
+    for conflict in validate_descriptors(&monsters, |_| None) {
+        warn!("{}", conflict);
+    }
+}
+
 fn add_game_camera(mut commands: Commands) {
     // we have a handle that's been created, so we can draw with it
     commands.spawn_bundle(Camera2dBundle {
@@ -0,0 +1,505 @@
+use bevy::log::warn;
+use bevy::math::Vec2;
+
+use crate::systems::audio::{AudioFeatures, AudioSignal};
+use crate::systems::expression::Expr;
+use crate::systems::motion::MotionSettings;
+use crate::systems::patterns::{hash, value_noise};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModTarget {
+    // fraction of the circle's radius
+    Radius,
+    // world units
+    PositionX,
+    PositionY,
+    // degrees
+    Hue,
+    // offsets in 0.0..=1.0; in the Oklch color space saturation is chroma, at a quarter the scale
+    Saturation,
+    Lightness,
+    Alpha,
+}
+
+// every waveform runs from -1.0 to 1.0, except Audio
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Waveform {
+    Sine,
+    Triangle,
+    Square,
+    // smooth random wandering, different for every circle
+    Noise,
+    // |tan| and |sin|, held to 0.0..=1.0: they only ever push one way, and |tan| snaps back
+    // from its peaks
+    AbsTangent,
+    AbsSine,
+    // a level of the audio in AudioInput, 0.0..=1.0; frequency and phase don't apply
+    Audio(AudioSignal),
+}
+
+// where in its cycle each circle's modulator is; the numbers are cycles per unit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    // every circle in step
+    Same,
+    // placement order
+    Index(f32),
+    Radius(f32),
+    // no phase offset, but bigger circles go faster: k more cycles per second per world unit of
+    // radius, on top of the modulator's frequency
+    RadiusRate(f32),
+    // the same for the circle's position, k more cycles per second per world unit from the center
+    PositionXRate(f32),
+    PositionYRate(f32),
+    // distance from the texture's center, in world units
+    Distance(f32),
+    // a fixed random phase per circle
+    Random,
+}
+
+// one oscillation applied to every circle of an animated texture
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Modulator {
+    pub target: ModTarget,
+    pub waveform: Waveform,
+    // cycles per second
+    pub frequency: f32,
+    pub amplitude: f32,
+    pub phase: Phase,
+}
+
+// a formula over the variables in expression::VARIABLES that sets a target outright, before
+// the modulators add to it; radius and position are in world units, and colors are in the
+// texture's color space the way the hue, sat and light variables are. formulas aren't capped by
+// reduced motion, though the flash analyzer still measures them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Formula {
+    pub target: ModTarget,
+    pub expr: String,
+    // 1-based line of the formula sheet it came from
+    pub line: usize,
+}
+
+// parses the formulas once, reporting the ones that don't parse and leaving them out
+pub fn parse_formulas(name: &str, formulas: &[Formula]) -> Vec<(ModTarget, Expr)> {
+    formulas
+        .iter()
+        .filter_map(|formula| match Expr::parse(&formula.expr) {
+            Ok(expr) => Some((formula.target, expr)),
+            Err(e) => {
+                warn!(
+                    "{}: {:?} formula on line {} ignored, {}",
+                    name,
+                    formula.target,
+                    formula.line,
+                    e.annotate(&formula.expr)
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Easing {
+    Linear,
+    #[default]
+    EaseOutCubic,
+    // overshoots a little before settling
+    EaseOutBack,
+}
+
+impl Easing {
+    // t in 0.0..=1.0 to progress, 0.0 at the start and 1.0 at the end
+    pub fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::EaseOutCubic => 1.0 - (1.0 - t).powi(3),
+            Easing::EaseOutBack => {
+                const OVERSHOOT: f32 = 1.701_58;
+                let u = t - 1.0;
+                1.0 + (OVERSHOOT + 1.0) * u.powi(3) + OVERSHOOT * u.powi(2)
+            }
+        }
+    }
+}
+
+// grows a packed texture's circles from nothing, one after another in packing order
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reveal {
+    // seconds, on the texture's clock, from the first circle starting to the last one done
+    pub duration: f32,
+    // seconds each circle takes to grow to full size
+    pub grow: f32,
+    pub easing: Easing,
+}
+
+impl Reveal {
+    // how far the circle at index, of count, has grown elapsed seconds into the reveal
+    pub fn scale(&self, elapsed: f32, index: usize, count: usize) -> f32 {
+        let grow = self
+            .grow
+            .clamp(f32::EPSILON, self.duration.max(f32::EPSILON));
+        // the last circle starts just in time to finish at duration
+        let start =
+            (self.duration - grow).max(0.0) * index as f32 / count.saturating_sub(1).max(1) as f32;
+        self.easing.apply((elapsed - start) / grow)
+    }
+
+    pub fn finished(&self, elapsed: f32) -> bool {
+        elapsed >= self.duration
+    }
+}
+
+// the circle a modulator is being sampled for
+#[derive(Clone, Copy, Debug)]
+pub struct CircleInfo {
+    pub index: usize,
+    pub radius: f32,
+    pub pos: Vec2,
+}
+
+// summed modulator outputs for one circle at one time
+#[derive(Default, Clone, Copy, Debug)]
+pub struct Modulation {
+    pub radius: f32,
+    pub offset: Vec2,
+    pub hue: f32,
+    pub saturation: f32,
+    pub lightness: f32,
+    pub alpha: f32,
+}
+
+impl Modulator {
+    fn phase(&self, circle: CircleInfo) -> f32 {
+        match self.phase {
+            Phase::Same
+            | Phase::RadiusRate(_)
+            | Phase::PositionXRate(_)
+            | Phase::PositionYRate(_) => 0.0,
+            Phase::Index(k) => k * circle.index as f32,
+            Phase::Radius(k) => k * circle.radius,
+            Phase::Distance(k) => k * circle.pos.length(),
+            Phase::Random => hash(circle.index as i32, 0, 0x5EED),
+        }
+    }
+
+    // the modulator's output, with reduced motion's caps on frequency and amplitude
+    pub fn sample(
+        &self,
+        t: f32,
+        circle: CircleInfo,
+        motion: &MotionSettings,
+        audio: &AudioFeatures,
+    ) -> f32 {
+        let rate = match self.phase {
+            Phase::RadiusRate(k) => k * circle.radius,
+            Phase::PositionXRate(k) => k * circle.pos.x,
+            Phase::PositionYRate(k) => k * circle.pos.y,
+            _ => 0.0,
+        };
+        let frequency = motion.frequency((self.frequency + rate) * std::f32::consts::TAU)
+            / std::f32::consts::TAU;
+        let x = frequency * t + self.phase(circle);
+        let wave = match self.waveform {
+            Waveform::Sine => (x * std::f32::consts::TAU).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (x.rem_euclid(1.0) - 0.5).abs(),
+            Waveform::Square => {
+                if x.rem_euclid(1.0) < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Noise => value_noise(x, circle.index as f32, 0x5EED) * 2.0 - 1.0,
+            Waveform::AbsTangent => (x * std::f32::consts::TAU).tan().abs().min(1.0),
+            Waveform::AbsSine => (x * std::f32::consts::TAU).sin().abs(),
+            Waveform::Audio(signal) => audio.get(signal),
+        };
+        let amplitude = match self.target {
+            ModTarget::Radius => motion.amplitude(self.amplitude, 0.03),
+            // no jitter at all in reduced motion
+            ModTarget::PositionX | ModTarget::PositionY => motion.amplitude(self.amplitude, 0.0),
+            ModTarget::Hue => motion.amplitude(self.amplitude, 10.0),
+            ModTarget::Saturation | ModTarget::Alpha => motion.amplitude(self.amplitude, 0.1),
+            ModTarget::Lightness => motion.lightness_swing(self.amplitude),
+        };
+        amplitude * wave
+    }
+}
+
+pub fn modulate(
+    modulators: &[Modulator],
+    t: f32,
+    circle: CircleInfo,
+    motion: &MotionSettings,
+    audio: &AudioFeatures,
+) -> Modulation {
+    let mut m = Modulation::default();
+    for modulator in modulators {
+        let v = modulator.sample(t, circle, motion, audio);
+        match modulator.target {
+            ModTarget::Radius => m.radius += v,
+            ModTarget::PositionX => m.offset.x += v,
+            ModTarget::PositionY => m.offset.y += v,
+            ModTarget::Hue => m.hue += v,
+            ModTarget::Saturation => m.saturation += v,
+            ModTarget::Lightness => m.lightness += v,
+            ModTarget::Alpha => m.alpha += v,
+        }
+    }
+    m
+}
+
+// the pulse, jitter and color swing Circles2 always had: radius times 1 + 0.12 sin(10 r t),
+// x plus 5 |tan(0.7 x t)| and y plus 3 |sin(3.1 y t)|, saturation plus 0.4 sin(3t) and lightness
+// plus 0.4 sin(5t)
+pub const CLASSIC: &[Modulator] = &[
+    Modulator {
+        target: ModTarget::Radius,
+        waveform: Waveform::Sine,
+        frequency: 0.0,
+        amplitude: 0.12,
+        phase: Phase::RadiusRate(10.0 / std::f32::consts::TAU),
+    },
+    Modulator {
+        target: ModTarget::PositionX,
+        waveform: Waveform::AbsTangent,
+        frequency: 0.0,
+        amplitude: 5.0,
+        phase: Phase::PositionXRate(0.7 / std::f32::consts::TAU),
+    },
+    Modulator {
+        target: ModTarget::PositionY,
+        waveform: Waveform::AbsSine,
+        frequency: 0.0,
+        amplitude: 3.0,
+        phase: Phase::PositionYRate(3.1 / std::f32::consts::TAU),
+    },
+    Modulator {
+        target: ModTarget::Saturation,
+        waveform: Waveform::Sine,
+        frequency: 3.0 / std::f32::consts::TAU,
+        amplitude: 0.4,
+        phase: Phase::Same,
+    },
+    Modulator {
+        target: ModTarget::Lightness,
+        waveform: Waveform::Sine,
+        frequency: 5.0 / std::f32::consts::TAU,
+        amplitude: 0.4,
+        phase: Phase::Same,
+    },
+];
+
+// CLASSIC with its jitter swapped for smooth noise of the same size, which wanders both ways
+// instead of jumping up and to the right
+pub const CLASSIC_SMOOTH: &[Modulator] = &[
+    Modulator {
+        target: ModTarget::Radius,
+        waveform: Waveform::Sine,
+        frequency: 0.0,
+        amplitude: 0.12,
+        phase: Phase::RadiusRate(10.0 / std::f32::consts::TAU),
+    },
+    Modulator {
+        target: ModTarget::PositionX,
+        waveform: Waveform::Noise,
+        frequency: 2.0,
+        amplitude: 5.0,
+        phase: Phase::Same,
+    },
+    Modulator {
+        target: ModTarget::PositionY,
+        waveform: Waveform::Noise,
+        frequency: 2.0,
+        amplitude: 3.0,
+        phase: Phase::Random,
+    },
+    Modulator {
+        target: ModTarget::Saturation,
+        waveform: Waveform::Sine,
+        frequency: 3.0 / std::f32::consts::TAU,
+        amplitude: 0.4,
+        phase: Phase::Same,
+    },
+    Modulator {
+        target: ModTarget::Lightness,
+        waveform: Waveform::Sine,
+        frequency: 5.0 / std::f32::consts::TAU,
+        amplitude: 0.4,
+        phase: Phase::Same,
+    },
+];
+
+// slow breathing
+pub const IDLE: &[Modulator] = &[
+    Modulator {
+        target: ModTarget::Radius,
+        waveform: Waveform::Sine,
+        frequency: 0.3,
+        amplitude: 0.05,
+        phase: Phase::Distance(0.002),
+    },
+    Modulator {
+        target: ModTarget::Lightness,
+        waveform: Waveform::Sine,
+        frequency: 0.2,
+        amplitude: 0.05,
+        phase: Phase::Random,
+    },
+];
+
+// ripples outward from the center, a little brighter
+pub const ALERT: &[Modulator] = &[
+    Modulator {
+        target: ModTarget::Radius,
+        waveform: Waveform::Sine,
+        frequency: 1.0,
+        amplitude: 0.1,
+        phase: Phase::Distance(-0.005),
+    },
+    Modulator {
+        target: ModTarget::Lightness,
+        waveform: Waveform::Triangle,
+        frequency: 1.0,
+        amplitude: 0.1,
+        phase: Phase::Distance(-0.005),
+    },
+    Modulator {
+        target: ModTarget::Saturation,
+        waveform: Waveform::Sine,
+        frequency: 0.5,
+        amplitude: 0.15,
+        phase: Phase::Same,
+    },
+];
+
+// fast shaking and a swinging hue
+pub const ENRAGED: &[Modulator] = &[
+    Modulator {
+        target: ModTarget::Radius,
+        waveform: Waveform::Noise,
+        frequency: 3.0,
+        amplitude: 0.15,
+        phase: Phase::Random,
+    },
+    Modulator {
+        target: ModTarget::PositionX,
+        waveform: Waveform::Noise,
+        frequency: 6.0,
+        amplitude: 4.0,
+        phase: Phase::Random,
+    },
+    Modulator {
+        target: ModTarget::PositionY,
+        waveform: Waveform::Noise,
+        frequency: 6.0,
+        amplitude: 4.0,
+        phase: Phase::Index(0.37),
+    },
+    Modulator {
+        target: ModTarget::Hue,
+        waveform: Waveform::Sine,
+        frequency: 0.5,
+        amplitude: 15.0,
+        phase: Phase::Same,
+    },
+    Modulator {
+        target: ModTarget::Saturation,
+        waveform: Waveform::Sine,
+        frequency: 1.5,
+        amplitude: 0.2,
+        phase: Phase::Index(0.01),
+    },
+];
+
+// pulses with the soundtrack: bass swells the circles, treble brightens them
+pub const RHYTHM: &[Modulator] = &[
+    Modulator {
+        target: ModTarget::Radius,
+        waveform: Waveform::Audio(AudioSignal::Bass),
+        frequency: 0.0,
+        amplitude: 0.3,
+        phase: Phase::Same,
+    },
+    Modulator {
+        target: ModTarget::Lightness,
+        waveform: Waveform::Audio(AudioSignal::Treble),
+        frequency: 0.0,
+        amplitude: 0.2,
+        phase: Phase::Same,
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REVEAL: Reveal = Reveal {
+        duration: 2.0,
+        grow: 0.5,
+        easing: Easing::Linear,
+    };
+
+    #[test]
+    fn the_first_circle_starts_at_once_and_the_last_finishes_at_the_end() {
+        assert!(REVEAL.scale(0.0, 0, 5).abs() < 1e-6);
+        assert!((REVEAL.scale(0.5, 0, 5) - 1.0).abs() < 1e-6);
+        assert!(REVEAL.scale(1.5, 4, 5).abs() < 1e-6);
+        assert!(REVEAL.scale(1.99, 4, 5) < 1.0);
+        assert!((REVEAL.scale(2.0, 4, 5) - 1.0).abs() < 1e-6);
+        // circles in between start evenly spaced
+        assert!((REVEAL.scale(0.625, 1, 5) - 0.5).abs() < 1e-6);
+        assert!(!REVEAL.finished(1.99));
+        assert!(REVEAL.finished(2.0));
+    }
+
+    #[test]
+    fn a_lone_circle_grows_straight_away() {
+        assert!((REVEAL.scale(0.25, 0, 1) - 0.5).abs() < 1e-6);
+        assert!((REVEAL.scale(0.5, 0, 0) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn growing_longer_than_the_reveal_grows_everything_at_once() {
+        let reveal = Reveal {
+            grow: 5.0,
+            ..REVEAL
+        };
+        for index in 0..3 {
+            assert!((reveal.scale(1.0, index, 3) - 0.5).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn classic_jitters_the_way_circles2_did() {
+        let circle = CircleInfo {
+            index: 3,
+            radius: 12.0,
+            pos: Vec2::new(40.0, -25.0),
+        };
+        let motion = MotionSettings::default();
+        for t in [0.1, 0.7, 2.3] {
+            let m = modulate(CLASSIC, t, circle, &motion, &AudioFeatures::default());
+            let x = 5.0 * (0.7 * 40.0 * t).tan().abs().clamp(0.0, 1.0);
+            let y = 3.0 * (3.1 * -25.0 * t).sin().abs().clamp(0.0, 1.0);
+            assert!((m.offset.x - x).abs() < 1e-3, "{t}");
+            assert!((m.offset.y - y).abs() < 1e-3, "{t}");
+            assert!(
+                (m.radius - 0.12 * (10.0 * 12.0 * t).sin()).abs() < 1e-3,
+                "{t}"
+            );
+        }
+    }
+
+    #[test]
+    fn easings_start_at_zero_and_end_at_one() {
+        for easing in [Easing::Linear, Easing::EaseOutCubic, Easing::EaseOutBack] {
+            assert!(easing.apply(-1.0).abs() < 1e-6, "{easing:?}");
+            assert!((easing.apply(2.0) - 1.0).abs() < 1e-6, "{easing:?}");
+        }
+        assert!(Easing::EaseOutBack.apply(0.8) > 1.0);
+    }
+}
@@ -0,0 +1,502 @@
+use std::collections::VecDeque;
+
+use bevy::asset::{
+    AssetLoader, AssetServer, Assets, BoxedFuture, Handle, LoadContext, LoadState, LoadedAsset,
+};
+use bevy::ecs::system::{Res, ResMut};
+use bevy::log::error;
+use bevy::reflect::TypeUuid;
+use bevy::time::Time;
+
+// samples analyzed at a time; a power of two for the FFT
+pub const WINDOW: usize = 1024;
+// band levels come out of decibels: this many below full scale reads 0.0
+const FLOOR_DB: f32 = 60.0;
+// how fast levels fall back, per second; they rise at once so beats land on time
+const RELEASE: f32 = 8.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioSignal {
+    // overall loudness
+    Amplitude,
+    // 20 to 250 Hz
+    Bass,
+    // 250 Hz to 4 kHz
+    Mid,
+    // 4 to 16 kHz
+    Treble,
+}
+
+impl AudioSignal {
+    fn band(self) -> Option<(f32, f32)> {
+        match self {
+            AudioSignal::Amplitude => None,
+            AudioSignal::Bass => Some((20.0, 250.0)),
+            AudioSignal::Mid => Some((250.0, 4000.0)),
+            AudioSignal::Treble => Some((4000.0, 16000.0)),
+        }
+    }
+}
+
+// levels of the audio playing now, each in 0.0..=1.0
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct AudioFeatures {
+    pub amplitude: f32,
+    pub bass: f32,
+    pub mid: f32,
+    pub treble: f32,
+}
+
+impl AudioFeatures {
+    pub fn get(&self, signal: AudioSignal) -> f32 {
+        match signal {
+            AudioSignal::Amplitude => self.amplitude,
+            AudioSignal::Bass => self.bass,
+            AudioSignal::Mid => self.mid,
+            AudioSignal::Treble => self.treble,
+        }
+    }
+
+    // rises to louder levels straight away and falls off smoothly over dt seconds
+    fn follow(&mut self, target: AudioFeatures, dt: f32) {
+        let fall = (-RELEASE * dt).exp();
+        for (level, target) in [
+            (&mut self.amplitude, target.amplitude),
+            (&mut self.bass, target.bass),
+            (&mut self.mid, target.mid),
+            (&mut self.treble, target.treble),
+        ] {
+            *level = if target > *level {
+                target
+            } else {
+                target + (*level - target) * fall
+            };
+        }
+    }
+}
+
+fn level(magnitude: f32) -> f32 {
+    ((20.0 * magnitude.max(1e-9).log10() + FLOOR_DB) / FLOOR_DB).clamp(0.0, 1.0)
+}
+
+// in-place radix-2 FFT of re and im, whose length is a power of two
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -std::f32::consts::TAU / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (wr, wi) = ((angle * k as f32).cos(), (angle * k as f32).sin());
+                let (a, b) = (start + k, start + k + len / 2);
+                let (tr, ti) = (re[b] * wr - im[b] * wi, re[b] * wi + im[b] * wr);
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        len *= 2;
+    }
+}
+
+// the features of the WINDOW samples ending at end, with silence before the first sample
+pub fn analyze(samples: &[f32], end: usize, sample_rate: u32) -> AudioFeatures {
+    let end = end.min(samples.len());
+    let mut re = vec![0.0; WINDOW];
+    let mut im = vec![0.0; WINDOW];
+    let available = end.min(WINDOW);
+    for (i, s) in samples[end - available..end].iter().enumerate() {
+        let slot = WINDOW - available + i;
+        // Hann window
+        let w = 0.5 - 0.5 * (std::f32::consts::TAU * slot as f32 / (WINDOW - 1) as f32).cos();
+        re[slot] = s * w;
+    }
+    let rms = (samples[end - available..end]
+        .iter()
+        .map(|s| s * s)
+        .sum::<f32>()
+        / WINDOW as f32)
+        .sqrt();
+    fft(&mut re, &mut im);
+
+    let bin_hz = sample_rate as f32 / WINDOW as f32;
+    let band = |signal: AudioSignal| {
+        let Some((low, high)) = signal.band() else {
+            return 0.0;
+        };
+        let first = ((low / bin_hz).ceil() as usize).max(1);
+        let last = ((high / bin_hz).floor() as usize).min(WINDOW / 2);
+        if last < first {
+            return 0.0;
+        }
+        // the strongest bin, scaled so a full scale sine reads 0 dB; the Hann window halves it
+        let peak = (first..=last)
+            .map(|k| (re[k] * re[k] + im[k] * im[k]).sqrt())
+            .fold(0.0, f32::max);
+        level(peak * 4.0 / WINDOW as f32)
+    };
+    AudioFeatures {
+        // a full scale sine has an RMS of 1/sqrt(2)
+        amplitude: level(rms * std::f32::consts::SQRT_2),
+        bass: band(AudioSignal::Bass),
+        mid: band(AudioSignal::Mid),
+        treble: band(AudioSignal::Treble),
+    }
+}
+
+// decoded audio, mixed down to one channel
+#[derive(Clone, Debug, TypeUuid)]
+#[uuid = "2f0790c8-94f0-4205-801c-9e29ea1b7467"]
+pub struct AudioClip {
+    pub sample_rate: u32,
+    pub samples: Vec<f32>,
+}
+
+impl AudioClip {
+    // uncompressed WAV: 8, 16, 24 or 32 bit integer PCM, or 32 bit float
+    pub fn from_wav(bytes: &[u8]) -> Result<AudioClip, String> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err("not a RIFF WAVE file".to_string());
+        }
+        let mut format = None;
+        let mut offset = 12;
+        while offset + 8 <= bytes.len() {
+            let id = &bytes[offset..offset + 4];
+            let size = u32::from_le_bytes([
+                bytes[offset + 4],
+                bytes[offset + 5],
+                bytes[offset + 6],
+                bytes[offset + 7],
+            ]) as usize;
+            let body = &bytes[offset + 8..(offset + 8 + size).min(bytes.len())];
+            match id {
+                b"fmt " if body.len() >= 16 => {
+                    let u16_at = |i: usize| u16::from_le_bytes([body[i], body[i + 1]]);
+                    let tag = u16_at(0);
+                    let channels = u16_at(2);
+                    let rate = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+                    let bits = u16_at(14);
+                    // the extensible format keeps the real tag at the start of its sub-format GUID
+                    let tag = if tag == 0xFFFE && body.len() >= 26 {
+                        u16_at(24)
+                    } else {
+                        tag
+                    };
+                    format = Some((tag, channels, rate, bits));
+                }
+                b"data" => {
+                    let (tag, channels, sample_rate, bits) =
+                        format.ok_or("data chunk before fmt chunk")?;
+                    if channels == 0 {
+                        return Err("no channels".to_string());
+                    }
+                    let decode: fn(&[u8]) -> f32 = match (tag, bits) {
+                        (1, 8) => |b| (f32::from(b[0]) - 128.0) / 128.0,
+                        (1, 16) => |b| f32::from(i16::from_le_bytes([b[0], b[1]])) / 32768.0,
+                        (1, 24) => |b| {
+                            (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8_388_608.0
+                        },
+                        (1, 32) => |b| {
+                            i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0
+                        },
+                        (3, 32) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+                        _ => return Err(format!("unsupported format {tag} with {bits} bits")),
+                    };
+                    let width = usize::from(bits / 8);
+                    let samples = body
+                        .chunks_exact(width * usize::from(channels))
+                        .map(|frame| {
+                            frame.chunks_exact(width).map(decode).sum::<f32>() / f32::from(channels)
+                        })
+                        .collect();
+                    return Ok(AudioClip {
+                        sample_rate,
+                        samples,
+                    });
+                }
+                _ => {}
+            }
+            // chunks are padded to an even length
+            offset += 8 + size + size % 2;
+        }
+        Err("no data chunk".to_string())
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.samples.len() as f32 / self.sample_rate.max(1) as f32
+    }
+
+    // the raw features of the window ending t seconds in; silence once the clip is over
+    pub fn features_at(&self, t: f32) -> AudioFeatures {
+        if t > self.duration() {
+            return AudioFeatures::default();
+        }
+        let end = (t.max(0.0) * self.sample_rate as f32) as usize;
+        analyze(&self.samples, end, self.sample_rate)
+    }
+}
+
+// decodes .clip.wav files for the audio analysis; playing them is up to the soundtrack, and
+// the double extension leaves plain .wav to bevy_audio's own loader
+#[derive(Default)]
+pub struct AudioClipLoader;
+
+impl AssetLoader for AudioClipLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let clip = AudioClip::from_wav(bytes).map_err(bevy::asset::Error::msg)?;
+            load_context.set_default_asset(LoadedAsset::new(clip));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["clip.wav"]
+    }
+}
+
+// the last WINDOW samples the game has played, pushed as it plays them
+pub struct AudioRing {
+    pub sample_rate: u32,
+    samples: VecDeque<f32>,
+}
+
+impl AudioRing {
+    pub fn new(sample_rate: u32) -> AudioRing {
+        AudioRing {
+            sample_rate,
+            samples: VecDeque::with_capacity(WINDOW),
+        }
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        for s in samples {
+            if self.samples.len() == WINDOW {
+                self.samples.pop_front();
+            }
+            self.samples.push_back(*s);
+        }
+    }
+
+    pub fn features(&self) -> AudioFeatures {
+        let samples: Vec<f32> = self.samples.iter().copied().collect();
+        analyze(&samples, samples.len(), self.sample_rate)
+    }
+}
+
+pub enum AudioSource {
+    // a clip the analysis plays through on the app clock from when it's set, not on the
+    // soundtrack's playback position, so it drifts from what's heard after a pause or seek
+    Clip { clip: AudioClip, start: f64 },
+    Ring(AudioRing),
+}
+
+// what audio-driven modulators react to; with no source every level stays at 0.0
+#[derive(Default)]
+pub struct AudioInput {
+    pub source: Option<AudioSource>,
+    pub features: AudioFeatures,
+    // a clip still loading, which becomes the source once it has
+    pending: Option<Handle<AudioClip>>,
+}
+
+impl AudioInput {
+    // follows a .clip.wav file in the assets folder from when it has loaded, alongside the
+    // soundtrack playing it
+    pub fn play_wav(&mut self, asset_server: &AssetServer, path: &str) {
+        self.pending = Some(asset_server.load(path));
+    }
+}
+
+pub fn resolve_pending_clip(
+    time: Res<Time>,
+    asset_server: Res<AssetServer>,
+    clips: Res<Assets<AudioClip>>,
+    mut input: ResMut<AudioInput>,
+) {
+    let Some(pending) = &input.pending else {
+        return;
+    };
+    if let Some(clip) = clips.get(pending) {
+        input.source = Some(AudioSource::Clip {
+            clip: clip.clone(),
+            start: time.seconds_since_startup(),
+        });
+        input.pending = None;
+    } else if asset_server.get_load_state(pending) == LoadState::Failed {
+        error!("couldn't load audio clip; keeping the audio input as it was");
+        input.pending = None;
+    }
+}
+
+pub fn analyze_audio(time: Res<Time>, mut input: ResMut<AudioInput>) {
+    let raw = match &input.source {
+        Some(AudioSource::Clip { clip, start }) => {
+            clip.features_at((time.seconds_since_startup() - start) as f32)
+        }
+        Some(AudioSource::Ring(ring)) => ring.features(),
+        None => AudioFeatures::default(),
+    };
+    input.features.follow(raw, time.delta_seconds());
+}
+
+// prints a WAV file's features over time, for tuning modulators without running the game
+pub fn run_analyze_audio_command(args: &[String]) -> Result<(), String> {
+    let usage = "usage: analyze-audio <file.wav> [--rate frames-per-second]";
+    let mut path = None;
+    let mut rate = 30.0;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rate" => {
+                rate = args
+                    .next()
+                    .and_then(|v| v.parse::<f32>().ok())
+                    .filter(|r| *r > 0.0)
+                    .ok_or_else(|| format!("--rate needs a positive number\n{usage}"))?;
+            }
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument \"{arg}\"\n{usage}")),
+        }
+    }
+    let path = path.ok_or_else(|| usage.to_string())?;
+    let bytes = std::fs::read(&path).map_err(|e| format!("can't read {path}: {e}"))?;
+    let clip = AudioClip::from_wav(&bytes).map_err(|e| format!("{path}: {e}"))?;
+
+    println!("time amplitude bass mid treble");
+    let mut features = AudioFeatures::default();
+    let frames = (clip.duration() * rate).ceil() as u32;
+    for frame in 0..frames {
+        let t = frame as f32 / rate;
+        features.follow(clip.features_at(t), 1.0 / rate);
+        println!(
+            "{:.3} {:.3} {:.3} {:.3} {:.3}",
+            t, features.amplitude, features.bass, features.mid, features.treble
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 44_100;
+
+    fn sine(hz: f32, seconds: f32) -> Vec<f32> {
+        (0..(seconds * RATE as f32) as usize)
+            .map(|i| (std::f32::consts::TAU * hz * i as f32 / RATE as f32).sin())
+            .collect()
+    }
+
+    // 16 bit PCM, every channel the same
+    fn wav(samples: &[f32], channels: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        for s in samples {
+            let s = (s * 32767.0) as i16;
+            for _ in 0..channels {
+                data.extend(s.to_le_bytes());
+            }
+        }
+        let mut fmt = Vec::new();
+        fmt.extend(1u16.to_le_bytes());
+        fmt.extend(channels.to_le_bytes());
+        fmt.extend(RATE.to_le_bytes());
+        fmt.extend((RATE * 2 * u32::from(channels)).to_le_bytes());
+        fmt.extend((2 * channels).to_le_bytes());
+        fmt.extend(16u16.to_le_bytes());
+        let mut bytes = b"RIFF\0\0\0\0WAVE".to_vec();
+        for (id, body) in [(b"fmt ", fmt), (b"data", data)] {
+            bytes.extend(id);
+            bytes.extend((body.len() as u32).to_le_bytes());
+            bytes.extend(body);
+        }
+        bytes
+    }
+
+    #[test]
+    fn decodes_wav_files_to_one_channel() {
+        let samples = sine(440.0, 0.5);
+        let clip = AudioClip::from_wav(&wav(&samples, 2)).unwrap();
+        assert_eq!(clip.sample_rate, RATE);
+        assert_eq!(clip.samples.len(), samples.len());
+        assert!((clip.duration() - 0.5).abs() < 1e-3);
+        assert!(clip
+            .samples
+            .iter()
+            .zip(&samples)
+            .all(|(a, b)| (a - b).abs() < 1e-3));
+    }
+
+    #[test]
+    fn rejects_files_that_arent_wav() {
+        assert_eq!(
+            AudioClip::from_wav(b"OggS").unwrap_err(),
+            "not a RIFF WAVE file"
+        );
+        let mut no_data = wav(&[], 1);
+        no_data.truncate(no_data.len() - 8);
+        assert_eq!(AudioClip::from_wav(&no_data).unwrap_err(), "no data chunk");
+    }
+
+    #[test]
+    fn a_sine_lights_up_its_own_band() {
+        for (hz, signal) in [
+            (100.0, AudioSignal::Bass),
+            (1000.0, AudioSignal::Mid),
+            (8000.0, AudioSignal::Treble),
+        ] {
+            let samples = sine(hz, 0.1);
+            let features = analyze(&samples, samples.len(), RATE);
+            assert!(features.amplitude > 0.95, "{hz} Hz: {features:?}");
+            for other in [AudioSignal::Bass, AudioSignal::Mid, AudioSignal::Treble] {
+                let level = features.get(other);
+                if other == signal {
+                    assert!(level > 0.9, "{hz} Hz: {features:?}");
+                } else {
+                    assert!(level < 0.7, "{hz} Hz: {features:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn goes_quiet_once_the_clip_is_over() {
+        let clip = AudioClip::from_wav(&wav(&sine(100.0, 0.5), 1)).unwrap();
+        assert!(clip.features_at(0.25).bass > 0.9);
+        assert_eq!(clip.features_at(0.6), AudioFeatures::default());
+        // before the start there's nothing to hear either
+        assert!(clip.features_at(0.0).amplitude < f32::EPSILON);
+    }
+
+    #[test]
+    fn levels_rise_at_once_and_fall_off() {
+        let mut features = AudioFeatures::default();
+        let loud = AudioFeatures {
+            bass: 1.0,
+            ..AudioFeatures::default()
+        };
+        features.follow(loud, 0.1);
+        assert!((features.bass - 1.0).abs() < f32::EPSILON);
+        features.follow(AudioFeatures::default(), 0.1);
+        assert!(features.bass > 0.0 && features.bass < 1.0);
+    }
+}
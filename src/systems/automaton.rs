@@ -0,0 +1,524 @@
+use bevy::asset::{Assets, Handle};
+use bevy::ecs::{
+    component::Component,
+    query::Without,
+    system::{Commands, Query, Res, ResMut},
+};
+use bevy::log::error;
+use bevy::render::{color::Color, texture::Image};
+use bevy::time::Time;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::systems::canvas::{spawn_canvas_sprite, upload_canvas, Canvas};
+use crate::systems::clock::{SimulationProgress, TextureClock};
+use crate::systems::color_generator::{ColorGenerator, ColorOptions};
+use crate::systems::color_palette::{PendingPalette, TexturePalette};
+use crate::systems::dynamic_textures::{
+    DynamicTextures, GeneratorParams, RenderToTextureDescriptor, StartColor,
+};
+use crate::systems::filters::{apply_filters, Filter};
+use crate::systems::motion::{canvas_luminance, FlashAnalyzer};
+
+// ages at or above this are drawn with the lightest shade
+const MAX_SHADED_AGE: u16 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AutomatonRule {
+    // life-like 2D rule; bit n set means n live neighbours cause a birth / let a cell survive
+    Life { birth: u16, survive: u16 },
+    // 1D Wolfram rule, drawn as a space-time diagram with the newest generation at the bottom
+    Elementary(u8),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuleParseError {
+    Empty,
+    UnexpectedChar { pos: usize, c: char },
+    DuplicateSection { pos: usize },
+    WolframOutOfRange(u32),
+}
+
+impl std::fmt::Display for RuleParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RuleParseError::Empty => write!(f, "empty rule"),
+            RuleParseError::UnexpectedChar { pos, c } => {
+                write!(f, "unexpected '{c}' at position {pos}")
+            }
+            RuleParseError::DuplicateSection { pos } => {
+                write!(f, "section repeated at position {pos}")
+            }
+            RuleParseError::WolframOutOfRange(n) => {
+                write!(f, "Wolfram rule {n} is not in 0..=255")
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for AutomatonRule {
+    type Err = RuleParseError;
+
+    // accepts "B3/S23" style rules (sections in either order, any case) and
+    // Wolfram rule numbers, either bare ("30") or prefixed ("W30", "Rule 30")
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim();
+        if trimmed.is_empty() {
+            return Err(RuleParseError::Empty);
+        }
+        let lower = trimmed.to_ascii_lowercase();
+        let number = lower
+            .strip_prefix("rule")
+            .or_else(|| lower.strip_prefix('w'))
+            .unwrap_or(&lower)
+            .trim();
+        if !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()) {
+            return match number.parse::<u32>() {
+                Ok(n) if n <= 255 => Ok(AutomatonRule::Elementary(n as u8)),
+                Ok(n) => Err(RuleParseError::WolframOutOfRange(n)),
+                Err(_) => Err(RuleParseError::WolframOutOfRange(u32::MAX)),
+            };
+        }
+
+        let mut birth: Option<u16> = None;
+        let mut survive: Option<u16> = None;
+        let mut in_birth = false;
+        for (pos, c) in s.char_indices() {
+            match c.to_ascii_lowercase() {
+                'b' | 's' => {
+                    in_birth = c.eq_ignore_ascii_case(&'b');
+                    let section = if in_birth { &mut birth } else { &mut survive };
+                    if section.is_some() {
+                        return Err(RuleParseError::DuplicateSection { pos });
+                    }
+                    *section = Some(0);
+                }
+                '0'..='8' => {
+                    let section = if in_birth { &mut birth } else { &mut survive };
+                    match section {
+                        Some(bits) => *bits |= 1 << c.to_digit(10).unwrap(),
+                        None => return Err(RuleParseError::UnexpectedChar { pos, c }),
+                    }
+                }
+                '/' | ' ' => {}
+                _ => return Err(RuleParseError::UnexpectedChar { pos, c }),
+            }
+        }
+        Ok(AutomatonRule::Life {
+            birth: birth.unwrap_or(0),
+            survive: survive.unwrap_or(0),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AutomatonParams {
+    pub rule: &'static str,
+    pub seed: u64,
+    // generations run before the texture is first shown
+    pub generations: u32,
+    // width and height of a cell in texture pixels
+    pub cell_size: u32,
+    // fraction of cells alive in the random first generation
+    pub density: f32,
+    pub animate: bool,
+    pub frames_per_generation: u32,
+}
+
+impl AutomatonParams {
+    pub const LIFE: AutomatonParams = AutomatonParams {
+        rule: "B3/S23",
+        seed: 1,
+        generations: 64,
+        cell_size: 4,
+        density: 0.3,
+        animate: true,
+        frames_per_generation: 6,
+    };
+    pub const RULE_30: AutomatonParams = AutomatonParams {
+        rule: "30",
+        density: 0.5,
+        frames_per_generation: 2,
+        ..AutomatonParams::LIFE
+    };
+}
+
+impl Default for AutomatonParams {
+    fn default() -> Self {
+        AutomatonParams::LIFE
+    }
+}
+
+// offsets to the cells either side along a wrapping axis n cells long, each cell once; on an
+// axis under 3 cells the left and right neighbours are the same cell, or the cell itself
+fn wrapped_offsets(n: usize) -> Vec<usize> {
+    match n {
+        1 => vec![0],
+        2 => vec![0, 1],
+        _ => vec![n - 1, 0, 1],
+    }
+}
+
+#[derive(Clone)]
+pub struct CellGrid {
+    width: usize,
+    height: usize,
+    rule: AutomatonRule,
+    // 0 is dead, otherwise the number of generations the cell has been alive
+    age: Vec<u16>,
+    next: Vec<u16>,
+}
+
+impl CellGrid {
+    pub fn new(width: u32, height: u32, rule: AutomatonRule, density: f32, seed: u64) -> CellGrid {
+        let (width, height) = (width as usize, height as usize);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut age = vec![0; width * height];
+        match rule {
+            AutomatonRule::Life { .. } => {
+                for a in &mut age {
+                    *a = u16::from(rng.gen::<f32>() < density);
+                }
+            }
+            AutomatonRule::Elementary(_) => {
+                // only the bottom row is live; the rows above are history
+                for a in &mut age[(height - 1) * width..] {
+                    *a = u16::from(rng.gen::<f32>() < density);
+                }
+            }
+        }
+        CellGrid {
+            width,
+            height,
+            rule,
+            next: age.clone(),
+            age,
+        }
+    }
+
+    pub fn is_alive(&self, x: usize, y: usize) -> bool {
+        self.age[y * self.width + x] > 0
+    }
+
+    pub fn step(&mut self) {
+        let (w, h) = (self.width, self.height);
+        match self.rule {
+            AutomatonRule::Life { birth, survive } => {
+                let (dxs, dys) = (wrapped_offsets(w), wrapped_offsets(h));
+                for y in 0..h {
+                    for x in 0..w {
+                        let mut neighbours = 0;
+                        for &dy in &dys {
+                            for &dx in &dxs {
+                                if (dx, dy) != (0, 0) && self.is_alive((x + dx) % w, (y + dy) % h) {
+                                    neighbours += 1;
+                                }
+                            }
+                        }
+                        let i = y * w + x;
+                        let mask = if self.age[i] > 0 { survive } else { birth };
+                        self.next[i] = if mask & (1 << neighbours) != 0 {
+                            self.age[i].saturating_add(1)
+                        } else {
+                            0
+                        };
+                    }
+                }
+            }
+            AutomatonRule::Elementary(rule) => {
+                // scroll the history up a row and compute the new bottom row
+                let last = (h - 1) * w;
+                self.next[..last].copy_from_slice(&self.age[w..]);
+                for x in 0..w {
+                    let pattern = u8::from(self.age[last + (x + w - 1) % w] > 0) << 2
+                        | u8::from(self.age[last + x] > 0) << 1
+                        | u8::from(self.age[last + (x + 1) % w] > 0);
+                    self.next[last + x] = if rule & (1 << pattern) != 0 {
+                        self.age[last + x].saturating_add(1)
+                    } else {
+                        0
+                    };
+                }
+            }
+        }
+        std::mem::swap(&mut self.age, &mut self.next);
+    }
+
+    pub fn run(&mut self, generations: u32) {
+        for _ in 0..generations {
+            self.step();
+        }
+    }
+
+    // live cells are shaded by age, dead ones show the background
+    pub fn render(
+        &self,
+        generator: &ColorGenerator,
+        background_color: Color,
+        cell_size: u32,
+        canvas: &mut Canvas,
+    ) {
+        let bg = background_color.as_rgba_f32();
+        for y in 0..canvas.height {
+            for x in 0..canvas.width {
+                let (cx, cy) = ((x / cell_size) as usize, (y / cell_size) as usize);
+                let c = if cx < self.width && cy < self.height {
+                    match self.age[cy * self.width + cx] {
+                        0 => bg,
+                        age => generator
+                            .shade(f32::from(age.min(MAX_SHADED_AGE)) / f32::from(MAX_SHADED_AGE))
+                            .as_rgba_f32(),
+                    }
+                } else {
+                    bg
+                };
+                canvas.set(x, y, c);
+            }
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct Automaton {
+    pub name: &'static str,
+    pub layer: u8,
+    pub size: u32,
+    pub start_color: StartColor,
+    pub background_color: Color,
+    pub color: ColorOptions,
+    pub params: AutomatonParams,
+    pub filters: &'static [Filter],
+    generator: Option<ColorGenerator>,
+    grid: Option<CellGrid>,
+    // the grid as it was set up, to start over from when the clock seeks back
+    initial: Option<CellGrid>,
+    // from the clock time it was set up at
+    progress: SimulationProgress,
+    canvas: Option<Canvas>,
+    image: Handle<Image>,
+    done_setup: bool,
+}
+
+impl Automaton {
+    pub fn new(layer: u8, desc: &RenderToTextureDescriptor) -> Automaton {
+        let params = match desc.params {
+            GeneratorParams::Automaton(params) => params,
+            _ => AutomatonParams::default(),
+        };
+        Automaton {
+            name: desc.name,
+            layer,
+            size: desc.size,
+            start_color: desc.start_color,
+            background_color: desc.background_color,
+            color: desc.color,
+            params,
+            filters: desc.filters,
+            generator: None,
+            grid: None,
+            initial: None,
+            progress: SimulationProgress::default(),
+            canvas: None,
+            image: Handle::default(),
+            done_setup: false,
+        }
+    }
+}
+
+pub fn automaton_setup(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut dyntex: ResMut<DynamicTextures>,
+    mut query: Query<
+        (&mut Automaton, &TextureClock, Option<&TexturePalette>),
+        Without<PendingPalette>,
+    >,
+) {
+    for (mut automaton, clock, palette) in &mut query {
+        if automaton.done_setup {
+            continue;
+        }
+        automaton.done_setup = true;
+        let params = automaton.params;
+        let rule = match params.rule.parse::<AutomatonRule>() {
+            Ok(rule) => rule,
+            Err(e) => {
+                error!("bad automaton rule \"{}\": {}", params.rule, e);
+                continue;
+            }
+        };
+        let (generator, _) = ColorGenerator::from_start_color(
+            &automaton.start_color,
+            automaton.color,
+            palette.map(|p| &p.0),
+        );
+        let cells = (automaton.size / params.cell_size.max(1)).max(1);
+        let mut grid = CellGrid::new(cells, cells, rule, params.density, params.seed);
+        grid.run(params.generations);
+
+        let mut canvas = Canvas::new(
+            automaton.size,
+            automaton.size,
+            automaton.background_color.as_rgba_f32(),
+        );
+        grid.render(
+            &generator,
+            automaton.background_color,
+            params.cell_size.max(1),
+            &mut canvas,
+        );
+        apply_filters(&mut canvas, automaton.filters);
+        automaton.image = spawn_canvas_sprite(&mut commands, &mut images, &canvas, automaton.layer);
+        dyntex.publish_canvas(automaton.name, canvas.clone());
+        automaton.generator = Some(generator);
+        automaton.initial = Some(grid.clone());
+        automaton.grid = Some(grid);
+        automaton.progress = SimulationProgress::new(clock.elapsed());
+        automaton.canvas = Some(canvas);
+    }
+}
+
+pub fn automaton_update(
+    time: Res<Time>,
+    mut images: ResMut<Assets<Image>>,
+    mut dyntex: ResMut<DynamicTextures>,
+    mut flashes: ResMut<FlashAnalyzer>,
+    mut query: Query<(&mut Automaton, &TextureClock)>,
+) {
+    for (mut automaton, clock) in &mut query {
+        if !automaton.done_setup || !automaton.params.animate {
+            continue;
+        }
+        let frames_per_generation = automaton.params.frames_per_generation.max(1);
+        let before = automaton.progress.frames() / frames_per_generation;
+        let (restart, _) = automaton.progress.advance(clock);
+        let before = if restart { 0 } else { before };
+        let generations = automaton.progress.frames() / frames_per_generation - before;
+        if generations == 0 && !restart {
+            continue;
+        }
+        let background_color = automaton.background_color;
+        let cell_size = automaton.params.cell_size.max(1);
+        let automaton = &mut *automaton;
+        if restart {
+            automaton.grid = automaton.initial.clone();
+        }
+        if let (Some(generator), Some(grid), Some(canvas)) = (
+            automaton.generator.as_ref(),
+            automaton.grid.as_mut(),
+            automaton.canvas.as_mut(),
+        ) {
+            for _ in 0..generations {
+                grid.step();
+            }
+            grid.render(generator, background_color, cell_size, canvas);
+            apply_filters(canvas, automaton.filters);
+            upload_canvas(&mut images, &automaton.image, canvas);
+            if dyntex.wants_canvas(automaton.name, automaton.filters) {
+                // measure_canvas_flashes samples it from there
+                dyntex.publish_canvas(automaton.name, canvas.clone());
+            } else {
+                flashes.record(
+                    automaton.name,
+                    time.seconds_since_startup() as f32,
+                    canvas_luminance(canvas),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIFE: AutomatonRule = AutomatonRule::Life {
+        birth: 1 << 3,
+        survive: 1 << 2 | 1 << 3,
+    };
+
+    #[test]
+    fn parses_life_rules_in_any_order_and_case() {
+        assert_eq!("B3/S23".parse(), Ok(LIFE));
+        assert_eq!("s23/b3".parse(), Ok(LIFE));
+        assert_eq!(
+            "B36/S23".parse(),
+            Ok(AutomatonRule::Life {
+                birth: 1 << 3 | 1 << 6,
+                survive: 1 << 2 | 1 << 3,
+            })
+        );
+        assert_eq!(
+            "B2/S".parse(),
+            Ok(AutomatonRule::Life {
+                birth: 1 << 2,
+                survive: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn parses_wolfram_rules() {
+        assert_eq!("30".parse(), Ok(AutomatonRule::Elementary(30)));
+        assert_eq!("W110".parse(), Ok(AutomatonRule::Elementary(110)));
+        assert_eq!("Rule 90".parse(), Ok(AutomatonRule::Elementary(90)));
+        assert_eq!(
+            "256".parse::<AutomatonRule>(),
+            Err(RuleParseError::WolframOutOfRange(256))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        assert_eq!("  ".parse::<AutomatonRule>(), Err(RuleParseError::Empty));
+        assert_eq!(
+            "B3/B4".parse::<AutomatonRule>(),
+            Err(RuleParseError::DuplicateSection { pos: 3 })
+        );
+        assert_eq!(
+            "B3x".parse::<AutomatonRule>(),
+            Err(RuleParseError::UnexpectedChar { pos: 2, c: 'x' })
+        );
+        assert_eq!(
+            "3/S23".parse::<AutomatonRule>(),
+            Err(RuleParseError::UnexpectedChar { pos: 0, c: '3' })
+        );
+        assert_eq!(
+            "B9".parse::<AutomatonRule>(),
+            Err(RuleParseError::UnexpectedChar { pos: 1, c: '9' })
+        );
+    }
+
+    #[test]
+    fn a_blinker_blinks() {
+        let mut grid = CellGrid::new(5, 5, LIFE, 0.0, 1);
+        for x in 1..4 {
+            grid.age[2 * 5 + x] = 1;
+        }
+        grid.step();
+        let alive: Vec<(usize, usize)> = (0..5)
+            .flat_map(|y| (0..5).map(move |x| (x, y)))
+            .filter(|(x, y)| grid.is_alive(*x, *y))
+            .collect();
+        assert_eq!(alive, vec![(2, 1), (2, 2), (2, 3)]);
+        grid.step();
+        assert!((1..4).all(|x| grid.is_alive(x, 2)));
+        // the middle cell has survived both generations
+        assert_eq!(grid.age[2 * 5 + 2], 3);
+    }
+
+    #[test]
+    fn rule_90_draws_a_sierpinski_triangle() {
+        let mut grid = CellGrid::new(7, 4, AutomatonRule::Elementary(90), 0.0, 1);
+        grid.age[3 * 7 + 3] = 1;
+        grid.run(3);
+        let row = |y: usize| -> String {
+            (0..7)
+                .map(|x| if grid.is_alive(x, y) { '#' } else { '.' })
+                .collect()
+        };
+        assert_eq!(row(0), "...#...");
+        assert_eq!(row(1), "..#.#..");
+        assert_eq!(row(2), ".#...#.");
+        assert_eq!(row(3), "#.#.#.#");
+    }
+}
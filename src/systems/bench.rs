@@ -0,0 +1,169 @@
+use bevy::app::{App, AppExit, Plugin};
+use bevy::asset::Assets;
+use bevy::ecs::{
+    event::EventWriter,
+    query::With,
+    schedule::ParallelSystemDescriptorCoercion,
+    system::{Query, Res, ResMut},
+};
+use bevy::log::info;
+use bevy::math::Vec3;
+use bevy::render::mesh::{shape, Mesh};
+use bevy::sprite::{ColorMaterial, Mesh2dHandle};
+use bevy::time::Time;
+use bevy::transform::components::Transform;
+
+use crate::systems::circles::{circles2_update, TextureCircle};
+use crate::systems::dynamic_textures::{AddDynamicTextureEvent, RenderToTextureDescriptor};
+
+// generates one texture and measures frame time and asset counts while it animates, first the
+// way it normally draws, then with a new mesh for every circle every frame, the way circles were
+// animated before they shared one, then quits
+pub struct BenchPlugin {
+    pub descriptor: RenderToTextureDescriptor,
+    // frames left out of the numbers while the texture sets up
+    pub warmup_frames: u32,
+    // frames measured each way
+    pub frames: u32,
+}
+
+struct BenchSettings {
+    descriptor: RenderToTextureDescriptor,
+    warmup_frames: u32,
+    frames: u32,
+}
+
+// the numbers for one way of drawing
+#[derive(Default)]
+struct BenchRun {
+    frame_times: Vec<f32>,
+    first_mesh_count: usize,
+    last_mesh_count: usize,
+    max_mesh_count: usize,
+}
+
+impl BenchRun {
+    fn record(&mut self, frame_time: f32, mesh_count: usize) {
+        if self.frame_times.is_empty() {
+            self.first_mesh_count = mesh_count;
+        }
+        self.last_mesh_count = mesh_count;
+        self.max_mesh_count = self.max_mesh_count.max(mesh_count);
+        self.frame_times.push(frame_time);
+    }
+
+    fn report(&self, settings: &BenchSettings, label: &str, materials: usize) -> f32 {
+        let mut sorted = self.frame_times.clone();
+        sorted.sort_by(f32::total_cmp);
+        let mean = sorted.iter().sum::<f32>() / sorted.len().max(1) as f32;
+        let p99 = sorted
+            .get((sorted.len() * 99 / 100).min(sorted.len().saturating_sub(1)))
+            .copied()
+            .unwrap_or(0.0);
+        let added = self.last_mesh_count as f32 - self.first_mesh_count as f32;
+        info!(
+            "bench {} ({}px, {}): {} frames, mean {:.2} ms, p99 {:.2} ms; meshes {} at start, {} at end, {} at most, {:.1} more per frame; {} materials",
+            settings.descriptor.name,
+            settings.descriptor.size,
+            label,
+            sorted.len(),
+            mean,
+            p99,
+            self.first_mesh_count,
+            self.last_mesh_count,
+            self.max_mesh_count,
+            added / sorted.len().max(1) as f32,
+            materials
+        );
+        mean
+    }
+}
+
+#[derive(Default)]
+struct BenchState {
+    frame: u32,
+    shared: BenchRun,
+    per_frame: BenchRun,
+}
+
+impl BenchState {
+    // whether circles get a fresh mesh every frame now
+    fn per_frame_meshes(&self, settings: &BenchSettings) -> bool {
+        self.frame > settings.warmup_frames + settings.frames
+    }
+}
+
+impl Plugin for BenchPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(BenchSettings {
+            descriptor: self.descriptor,
+            warmup_frames: self.warmup_frames,
+            frames: self.frames,
+        })
+        .init_resource::<BenchState>()
+        .add_startup_system(start_bench)
+        .add_system(measure_bench)
+        // after the circles have their size for the frame
+        .add_system(recreate_circle_meshes.after(circles2_update));
+    }
+}
+
+fn start_bench(settings: Res<BenchSettings>, mut ew: EventWriter<AddDynamicTextureEvent>) {
+    ew.send(AddDynamicTextureEvent {
+        description: Some(settings.descriptor),
+    });
+}
+
+// the old animation path: a new mesh of the circle's radius every frame, drawn unscaled
+fn recreate_circle_meshes(
+    settings: Res<BenchSettings>,
+    state: Res<BenchState>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut circles: Query<(&mut Mesh2dHandle, &mut Transform), With<TextureCircle>>,
+) {
+    if !state.per_frame_meshes(&settings) {
+        return;
+    }
+    for (mut mesh, mut tr) in &mut circles {
+        *mesh = meshes
+            .add(Mesh::from(shape::Circle::new(tr.scale.x)))
+            .into();
+        tr.scale = Vec3::ONE;
+    }
+}
+
+fn measure_bench(
+    settings: Res<BenchSettings>,
+    time: Res<Time>,
+    meshes: Res<Assets<Mesh>>,
+    materials: Res<Assets<ColorMaterial>>,
+    mut state: ResMut<BenchState>,
+    mut exit: EventWriter<AppExit>,
+) {
+    state.frame += 1;
+    if state.frame <= settings.warmup_frames {
+        return;
+    }
+    let frame_time = time.delta_seconds() * 1000.0;
+    if state.per_frame_meshes(&settings) {
+        state.per_frame.record(frame_time, meshes.len());
+    } else {
+        state.shared.record(frame_time, meshes.len());
+    }
+
+    if state.per_frame.frame_times.len() as u32 >= settings.frames {
+        let shared = state
+            .shared
+            .report(&settings, "shared mesh", materials.len());
+        let per_frame =
+            state
+                .per_frame
+                .report(&settings, "mesh per circle per frame", materials.len());
+        info!(
+            "bench {}: the shared mesh takes {:.0}% of the per-frame mesh time",
+            settings.descriptor.name,
+            100.0 * shared / per_frame.max(f32::EPSILON)
+        );
+        exit.send(AppExit);
+    }
+}
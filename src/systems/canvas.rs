@@ -0,0 +1,106 @@
+use bevy::asset::{Assets, Handle};
+use bevy::ecs::{component::Component, system::Commands};
+use bevy::render::{
+    render_resource::{Extent3d, TextureDimension, TextureFormat},
+    texture::Image,
+    view::RenderLayers,
+};
+use bevy::sprite::SpriteBundle;
+use bevy::utils::default;
+
+// CPU-side RGBA pixel buffer for generators that compute their texture on the CPU.
+// Pixels are stored row-major, top row first, as non-linear sRGB components in 0.0..=1.0
+#[derive(Clone, Debug)]
+pub struct Canvas {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[f32; 4]>,
+    // which palette color each pixel is, left by quantizing and dropped by any later filter
+    pub palette_indices: Option<Vec<u8>>,
+}
+
+impl Canvas {
+    pub fn new(width: u32, height: u32, fill: [f32; 4]) -> Canvas {
+        Canvas {
+            width,
+            height,
+            pixels: vec![fill; (width * height) as usize],
+            palette_indices: None,
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> [f32; 4] {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, c: [f32; 4]) {
+        self.pixels[(y * self.width + x) as usize] = c;
+    }
+
+    // paints color over the pixel with the given coverage, ignoring the color of transparent pixels
+    pub fn paint(&mut self, x: u32, y: u32, color: [f32; 4], coverage: f32) {
+        let p = &mut self.pixels[(y * self.width + x) as usize];
+        let alpha = color[3] * coverage;
+        for ch in 0..3 {
+            p[ch] = if p[3] > 0.0 {
+                p[ch] + (color[ch] - p[ch]) * alpha
+            } else {
+                color[ch]
+            };
+        }
+        p[3] += alpha * (1.0 - p[3]);
+    }
+
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.pixels.len() * 4);
+        for p in &self.pixels {
+            for channel in p {
+                data.push((channel.clamp(0.0, 1.0) * 255.0).round() as u8);
+            }
+        }
+        data
+    }
+
+    pub fn to_image(&self) -> Image {
+        Image::new(
+            Extent3d {
+                width: self.width,
+                height: self.height,
+                ..default()
+            },
+            TextureDimension::D2,
+            self.to_rgba8(),
+            TextureFormat::Rgba8UnormSrgb,
+        )
+    }
+}
+
+// marks the sprites spawn_canvas_sprite puts on render layers
+#[derive(Component)]
+pub struct CanvasSprite;
+
+// adds the canvas as an image and puts a sprite showing it on the given render layer,
+// centered so that it exactly covers the layer's render target
+pub fn spawn_canvas_sprite(
+    commands: &mut Commands,
+    images: &mut Assets<Image>,
+    canvas: &Canvas,
+    layer: u8,
+) -> Handle<Image> {
+    let handle = images.add(canvas.to_image());
+    commands
+        .spawn_bundle(SpriteBundle {
+            texture: handle.clone(),
+            ..default()
+        })
+        .insert(RenderLayers::layer(layer))
+        .insert(CanvasSprite);
+    handle
+}
+
+// replaces the pixels of an image previously created by spawn_canvas_sprite
+pub fn upload_canvas(images: &mut Assets<Image>, handle: &Handle<Image>, canvas: &Canvas) {
+    if let Some(image) = images.get_mut(handle) {
+        image.data = canvas.to_rgba8();
+    }
+}
@@ -1,24 +1,50 @@
-use bevy::asset::{Assets};
+use bevy::asset::{Assets, Handle};
 use bevy::ecs::{
     component::Component,
+    entity::Entity,
+    event::{EventReader, EventWriter},
+    query::Without,
     system::{Commands, Query, Res, ResMut},
+    world::{FromWorld, World},
 };
+use bevy::log::{info, warn};
 use bevy::math::{Vec2, Vec3};
-use bevy::render::{color::Color, mesh::shape, mesh::Mesh, view::RenderLayers};
-use bevy::sprite::{ColorMaterial, MaterialMesh2dBundle, Mesh2dHandle};
+use bevy::render::{
+    color::Color,
+    mesh::shape,
+    mesh::Mesh,
+    texture::Image,
+    view::{RenderLayers, Visibility},
+};
+use bevy::sprite::{ColorMaterial, MaterialMesh2dBundle};
 use bevy::time::Time;
 use bevy::transform::components::Transform;
-use bevy::utils::default;
-use palette::{rgb::Rgb, FromColor, Hsl, Srgb};
+use bevy::utils::{default, HashMap};
 use rand::Rng;
 //use bevy::prelude::*;
 
-use crate::systems::color_generator;
-use crate::systems::dynamic_textures::StartColor;
+use crate::systems::animation::{modulate, CircleInfo, ModTarget, Modulation, Modulator, Reveal};
+use crate::systems::audio::{AudioFeatures, AudioInput};
+use crate::systems::canvas::{spawn_canvas_sprite, upload_canvas, Canvas};
+use crate::systems::clock::{SimulationProgress, TextureClock, NOMINAL_FPS};
+use crate::systems::color_generator::{
+    animate_color, color_components, ColorGenerator, ColorOptions,
+};
+use crate::systems::color_palette::{ColorPalette, PendingPalette, TexturePalette};
+use crate::systems::dynamic_textures::{DynamicTextures, GeneratorParams, StartColor};
+use crate::systems::expression::Expr;
+use crate::systems::filters::{apply_filters, needs_canvas, ColorFilters, Filter};
+use crate::systems::morph::Morph;
+use crate::systems::motion::{relative_luminance, FlashAnalyzer, MotionSettings};
+use crate::systems::physics::{PhysicsParams, PhysicsWorld};
+use crate::systems::reactions::Reactions;
 
 use super::dynamic_textures::RenderToTextureDescriptor;
 
 const MIN_RADIUS: f32 = 4.0;
+const MAX_RADIUS: f32 = 20.0;
+// width of the square circles are packed into
+const PACKING_WIDTH: f32 = 1280.0;
 const MAX_CIRCLES_PER_RADIUS: u32 = 100;
 
 #[derive(Debug)]
@@ -40,16 +66,69 @@ fn intersects_any(c: &MyCircle, cv: &[MyCircle]) -> bool {
     false
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CycleDirection {
+    Forward,
+    Backward,
+    // forward through the range, then back
+    PingPong,
+}
+
+// Circles1 keeps every circle's own color in a lookup, in placement order, and gives each circle
+// an offset into it; cycling rotates the offsets, so the circles pass their colors along
+#[derive(Clone, Copy, Debug)]
+pub struct PaletteCycle {
+    // lookup entries per second; 0.0 stops cycling
+    pub speed: f32,
+    pub direction: CycleDirection,
+    // the lookup entries start..end that rotate, or all of them
+    pub range: Option<(u32, u32)>,
+}
+
+impl PaletteCycle {
+    pub const DEFAULT: PaletteCycle = PaletteCycle {
+        speed: 30.0,
+        direction: CycleDirection::Forward,
+        range: None,
+    };
+
+    // how far the colors in a range of len slots have moved after offset slots of travel
+    fn shift(&self, offset: f32, len: usize) -> usize {
+        let steps = offset.floor() as usize;
+        match self.direction {
+            CycleDirection::Forward => steps % len,
+            CycleDirection::Backward => (len - steps % len) % len,
+            CycleDirection::PingPong => {
+                let span = (len - 1).max(1);
+                let phase = steps % (2 * span);
+                if phase <= span {
+                    phase
+                } else {
+                    2 * span - phase
+                }
+            }
+        }
+    }
+}
+
+impl Default for PaletteCycle {
+    fn default() -> Self {
+        PaletteCycle::DEFAULT
+    }
+}
+
 pub fn circles1_add_circles_to_layer(
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
+    circle_mesh: Res<CircleMesh>,
     mut materials: ResMut<Assets<ColorMaterial>>,
-    mut query: Query<&mut Circles1>,
+    mut images: ResMut<Assets<Image>>,
+    mut dyntex: ResMut<DynamicTextures>,
+    mut query: Query<(Entity, &mut Circles1, Option<&TexturePalette>), Without<PendingPalette>>,
 ) {
     if query.is_empty() {
         return;
     }
-    for mut circles1 in &mut query {
+    for (owner, mut circles1, palette) in &mut query {
         if circles1.done_setup {
             continue;
         }
@@ -57,14 +136,14 @@ pub fn circles1_add_circles_to_layer(
         let window_width = 1280; //windows.primary().physical_width();
 
         let mut circs: Vec<MyCircle> = Vec::new();
-        let mut r = 20.0;
+        let mut r = MAX_RADIUS;
 
         let mut rng = rand::thread_rng();
 
-        let (mut generator, mut current_color) = color_generator::ColorGenerator::new(
-            circles1.start_color.hue,
-            circles1.start_color.saturation,
-            circles1.start_color.lightness,
+        let (mut generator, mut current_color) = ColorGenerator::from_start_color(
+            &circles1.start_color,
+            circles1.color,
+            palette.map(|p| &p.0),
         );
 
         let mut color_change_count = 0;
@@ -80,12 +159,26 @@ pub fn circles1_add_circles_to_layer(
                     rng.gen::<f32>() * (window_width as f32 - r * 2.0) + r
                         - window_width as f32 / 2.0,
                 );
-                let nc = MyCircle {
+                let mut nc = MyCircle {
                     pos: npos,
                     r,
                     c: current_color,
                 };
                 if !intersects_any(&nc, &circs) {
+                    nc.c = mapped_color(
+                        &generator,
+                        circles1.color,
+                        (npos, r),
+                        circles1.size,
+                        current_color,
+                    );
+                    let others = contrast_neighbours(
+                        npos,
+                        r,
+                        circs.iter().map(|c| (c.pos, c.r, c.c)),
+                        Some(circles1.background_color),
+                    );
+                    nc.c = generator.constrain(nc.c, &others);
                     circs.push(nc);
                     success = true;
                     circles_of_this_radius += 1;
@@ -112,52 +205,85 @@ pub fn circles1_add_circles_to_layer(
             }
         }
 
-        for c in &circs {
+        report_adjusted(circles1.name, &generator);
+
+        circles1.set_circles(&circs, &mut materials);
+        let canvas = circles1.canvas(&circles1.circles.c);
+        circles1.show_canvas_if_needed(&mut commands, &mut images, &canvas);
+        // a snapshot for textures that use this one as an input
+        dyntex.publish_canvas(circles1.name, canvas);
+
+        for (i, c) in circs.iter().enumerate() {
             commands
                 .spawn_bundle(MaterialMesh2dBundle {
-                    mesh: meshes.add(shape::Circle::new(c.r).into()).into(),
-                    material: materials.add(ColorMaterial::from(c.c)),
-                    transform: Transform::from_translation(Vec3::new(c.pos.x, c.pos.y, 0.0)),
+                    mesh: circle_mesh.0.clone().into(),
+                    material: circles1.materials[i].clone(),
+                    transform: Transform::from_translation(Vec3::new(c.pos.x, c.pos.y, 0.0))
+                        .with_scale(Vec3::splat(c.r)),
+                    // the canvas sprite shows them instead
+                    visibility: Visibility {
+                        is_visible: circles1.image.is_none(),
+                    },
                     ..default()
                 })
-                .insert(first_pass_layer);
+                .insert(first_pass_layer)
+                .insert(TextureCircle { owner, index: i });
         }
         circles1.done_setup = true;
     }
 }
 
-//#[allow(clippy::manual_swap)]
+// rotates each Circles1's colors through its own circles' materials
 pub fn circles1_update_colors(
     mut materials: ResMut<Assets<ColorMaterial>>,
-    mut query: Query<&mut Circles1>,
+    mut images: ResMut<Assets<Image>>,
+    mut dyntex: ResMut<DynamicTextures>,
+    query: Query<(&Circles1, &TextureClock)>,
 ) {
     if query.is_empty() {
         return;
     }
-    for circles1 in &mut query {
-        if !circles1.done_setup {
+    for (circles1, clock) in &query {
+        if !circles1.done_setup || circles1.materials.is_empty() || !clock.changed() {
             continue;
         }
-        // get the color of the last circle in the list
-        let (_, saved_color_mat) = materials.iter_mut().last().unwrap();
-        let mut prev_color = saved_color_mat.color;
-        // rotate all the colors around through all ColorMaterials
-        for m in materials.iter_mut() {
-            std::mem::swap(&mut m.1.color, &mut prev_color);
+        let Some(colors) = circles1.cycled_colors(clock.elapsed()) else {
+            continue;
+        };
+        for (i, color) in colors.iter().enumerate() {
+            if let Some(material) = materials.get_mut(&circles1.materials[i]) {
+                material.color =
+                    circles1
+                        .color_filters
+                        .apply(*color, circles1.circles.pos[i], circles1.size);
+            }
+        }
+        let published = dyntex.wants_canvas(circles1.name, circles1.filters);
+        if circles1.image.is_none() && !published {
+            continue;
+        }
+        let canvas = circles1.canvas(&colors);
+        if let Some(image) = &circles1.image {
+            upload_canvas(&mut images, image, &canvas);
+        }
+        if published {
+            dyntex.publish_canvas(circles1.name, canvas);
         }
     }
 }
 
 //------------------------------------------------------
 
-struct AllCircles {
-    pos: Vec<Vec2>,
-    r: Vec<f32>,
-    c: Vec<Color>,
+// every circle of a packed texture, in placement order
+#[derive(Default)]
+pub struct AllCircles {
+    pub pos: Vec<Vec2>,
+    pub r: Vec<f32>,
+    pub c: Vec<Color>,
 }
 
 impl AllCircles {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             pos: Vec::new(),
             r: Vec::new(),
@@ -166,169 +292,830 @@ impl AllCircles {
     }
 }
 
+// the mesh every circle is drawn with, scaled to its radius by its Transform
+pub struct CircleMesh(pub Handle<Mesh>);
+
+impl FromWorld for CircleMesh {
+    fn from_world(world: &mut World) -> Self {
+        let mut meshes = world.resource_mut::<Assets<Mesh>>();
+        CircleMesh(meshes.add(shape::Circle::new(1.0).into()))
+    }
+}
+
+// sent once a Circles2 texture's reveal has grown every circle to full size
+pub struct RevealFinished {
+    pub name: &'static str,
+}
+
+// shrinks a Circles2 texture's circles to nothing and grows them in again, with reveal or, when
+// that's None, the reveal the texture already has
+pub struct RestartReveal {
+    pub name: String,
+    pub reveal: Option<Reveal>,
+}
+
+// on every circle a texture spawns, so its animation only touches its own circles
+#[derive(Component)]
+pub struct TextureCircle {
+    // the entity with the Circles1 or Circles2 component
+    pub owner: Entity,
+    // which of the owner's circles this is, in placement order
+    pub index: usize,
+}
+
 #[derive(Component)]
 pub struct Circles1 {
+    pub name: &'static str,
     pub layer: u8,
+    pub size: u32,
     pub start_color: StartColor,
     pub background_color: Color,
+    pub color: ColorOptions,
+    pub filters: &'static [Filter],
+    color_filters: ColorFilters,
+    pub cycle: PaletteCycle,
+    // every circle, in placement order; their unfiltered colors are the cycle's lookup
+    circles: AllCircles,
+    // each circle's entry in the lookup before any cycling
+    offsets: Vec<usize>,
+    materials: Vec<Handle<ColorMaterial>>,
+    // the canvas sprite showing the texture in place of its circle meshes, when its filters need
+    // the whole picture
+    image: Option<Handle<Image>>,
     done_setup: bool,
 }
 
 impl Circles1 {
     pub fn new(layer: u8, desc: &RenderToTextureDescriptor) -> Circles1 {
         Circles1 {
+            name: desc.name,
             layer,
+            size: desc.size,
             start_color: desc.start_color,
             background_color: desc.background_color,
+            color: desc.color,
+            filters: desc.filters,
+            color_filters: ColorFilters::new(desc.filters),
+            image: None,
+            cycle: match desc.params {
+                GeneratorParams::PaletteCycle(cycle) => cycle,
+                _ => PaletteCycle::default(),
+            },
+            circles: AllCircles::new(),
+            offsets: Vec::new(),
+            materials: Vec::new(),
             done_setup: false,
         }
     }
+
+    // every circle starts on its own color, with its own material; per-color filters are applied
+    // where it is
+    fn set_circles(&mut self, circs: &[MyCircle], materials: &mut Assets<ColorMaterial>) {
+        self.circles = AllCircles {
+            pos: circs.iter().map(|c| c.pos).collect(),
+            r: circs.iter().map(|c| c.r).collect(),
+            c: circs.iter().map(|c| c.c).collect(),
+        };
+        self.offsets = (0..circs.len()).collect();
+        self.materials = circs
+            .iter()
+            .map(|c| {
+                let color = self.color_filters.apply(c.c, c.pos, self.size);
+                materials.add(ColorMaterial::from(color))
+            })
+            .collect();
+    }
+
+    // each circle's color elapsed seconds into the cycle, or None when the range is too short to
+    // cycle
+    fn cycled_colors(&self, elapsed: f32) -> Option<Vec<Color>> {
+        let cycle = self.cycle;
+        let lookup = &self.circles.c;
+        let len = lookup.len() as u32;
+        let (start, end) = cycle.range.map_or((0, len), |(start, end)| {
+            (start.min(len), end.clamp(start.min(len), len))
+        });
+        let (start, end) = (start as usize, end as usize);
+        if end - start < 2 {
+            return None;
+        }
+        let shift = cycle.shift(cycle.speed * elapsed, end - start);
+        Some(
+            self.offsets
+                .iter()
+                .map(|offset| {
+                    if (start..end).contains(offset) {
+                        lookup[start + (offset - start + shift) % (end - start)]
+                    } else {
+                        lookup[*offset]
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    // the texture drawn on the CPU with the given circle colors
+    fn canvas(&self, colors: &[Color]) -> Canvas {
+        let circles = AllCircles {
+            pos: self.circles.pos.clone(),
+            r: self.circles.r.clone(),
+            c: colors.to_vec(),
+        };
+        circles_canvas(&circles, self.size, self.background_color, self.filters)
+    }
+
+    // from now on draws the texture on the CPU, starting with canvas, if its filters need that
+    fn show_canvas_if_needed(
+        &mut self,
+        commands: &mut Commands,
+        images: &mut Assets<Image>,
+        canvas: &Canvas,
+    ) {
+        if self.image.is_none() && needs_canvas(self.filters) {
+            self.image = Some(spawn_canvas_sprite(commands, images, canvas, self.layer));
+        }
+    }
     // drop() ... gets rid of setup/update systems
 }
 
 #[derive(Component)]
 pub struct Circles2 {
+    pub name: &'static str,
     pub layer: u8,
+    pub size: u32,
     pub start_color: StartColor,
     pub background_color: Color,
+    pub color: ColorOptions,
+    pub filters: &'static [Filter],
+    color_filters: ColorFilters,
+    pub animation: &'static [Modulator],
+    pub reveal: Option<Reveal>,
+    pub physics_params: Option<PhysicsParams>,
+    formulas: Vec<(ModTarget, Expr)>,
     allcircs: AllCircles,
+    // where the circles have been knocked to, when physics_params is set
+    physics: Option<PhysicsWorld>,
+    // from the clock time the simulation started at
+    physics_progress: SimulationProgress,
+    reactions: Reactions,
+    // the canvas sprite showing the texture in place of its circle meshes, when its filters need
+    // the whole picture
+    image: Option<Handle<Image>>,
+    // clock time the reveal started at, and whether RevealFinished has gone out
+    reveal_start: f32,
+    revealed: bool,
     done_setup: bool,
 }
 
 impl Circles2 {
     pub fn new(layer: u8, desc: &RenderToTextureDescriptor) -> Circles2 {
         Circles2 {
+            name: desc.name,
             layer,
+            size: desc.size,
             start_color: desc.start_color,
             background_color: desc.background_color,
+            color: desc.color,
+            filters: desc.filters,
+            color_filters: ColorFilters::new(desc.filters),
+            animation: desc.animation,
+            reveal: desc.reveal,
+            physics_params: physics_params(desc),
+            // set from the descriptor's formula sheet once it loads
+            formulas: Vec::new(),
             allcircs: AllCircles::new(),
+            physics: None,
+            physics_progress: SimulationProgress::default(),
+            reactions: Reactions::new(0),
+            image: None,
+            reveal_start: 0.0,
+            revealed: false,
             done_setup: false,
         }
     }
+
+    pub fn is_set_up(&self) -> bool {
+        self.done_setup
+    }
+
+    pub fn circles(&self) -> &AllCircles {
+        &self.allcircs
+    }
+
+    // runs the formulas for one circle, returning its radius and position and adding its color
+    // changes to modulation
+    fn apply_formulas(
+        &self,
+        t: f32,
+        index: usize,
+        (r, p, c): (f32, Vec2, Color),
+        levels: &AudioFeatures,
+        modulation: &mut Modulation,
+    ) -> (f32, Vec2) {
+        let (mut radius, mut pos) = (r, p);
+        if self.formulas.is_empty() {
+            return (radius, pos);
+        }
+        let [hue, sat, light] = color_components(c, self.color.space);
+        let vars = [
+            t,
+            r,
+            p.x,
+            p.y,
+            index as f32,
+            hue,
+            sat,
+            light,
+            levels.amplitude,
+            levels.bass,
+            levels.mid,
+            levels.treble,
+        ];
+        for (target, expr) in &self.formulas {
+            let value = expr.eval(&vars);
+            match target {
+                ModTarget::Radius => radius = value,
+                ModTarget::PositionX => pos.x = value,
+                ModTarget::PositionY => pos.y = value,
+                // as offsets, since animate_color works from the circle's own color
+                ModTarget::Hue => modulation.hue += value - hue,
+                ModTarget::Saturation => modulation.saturation += value - sat,
+                ModTarget::Lightness => modulation.lightness += value - light,
+                ModTarget::Alpha => modulation.alpha += value - c.a(),
+            }
+        }
+        (radius, pos)
+    }
+
+    // from now on draws the texture on the CPU, starting with canvas, if its filters need that;
+    // a texture that has switched over stays drawn that way
+    pub fn show_canvas_if_needed(
+        &mut self,
+        commands: &mut Commands,
+        images: &mut Assets<Image>,
+        canvas: &Canvas,
+    ) {
+        if self.image.is_none() && needs_canvas(self.filters) {
+            self.image = Some(spawn_canvas_sprite(commands, images, canvas, self.layer));
+        }
+    }
+
+    // overall luminance of the texture, from its circles' area-weighted luminance and the area
+    // they cover, with the rest showing background
+    fn luminance(&self, lit_area: f32, covered: f32, background: Color) -> f32 {
+        let total = (self.size * self.size) as f32;
+        let background = relative_luminance(background.as_rgba_f32());
+        (lit_area + background * (total - covered).max(0.0)) / total
+    }
+
+    pub fn set_formulas(&mut self, formulas: Vec<(ModTarget, Expr)>) {
+        self.formulas = formulas;
+    }
+
+    pub fn physics_mut(&mut self) -> Option<&mut PhysicsWorld> {
+        self.physics.as_mut()
+    }
+
+    pub fn reactions_mut(&mut self) -> &mut Reactions {
+        &mut self.reactions
+    }
+
+    // reactions play out in real time, so a paused texture still shows a hit
+    fn needs_redraw(&self, clock: &TextureClock) -> bool {
+        self.done_setup && (clock.changed() || self.reactions.changed())
+    }
+
+    // a fresh simulation of the current layout, if the texture has physics, starting at clock
+    // time start
+    fn reset_physics(&mut self, start: f32) {
+        self.physics_progress = SimulationProgress::new(start);
+        self.restart_physics();
+    }
+
+    // the simulation back where it started, keeping its progress
+    fn restart_physics(&mut self) {
+        self.physics = self.physics_params.map(|params| {
+            PhysicsWorld::new(
+                &self.allcircs.pos,
+                &self.allcircs.r,
+                // the packing is wider than the texture; only circles inside the visible part move
+                self.size as f32 / 2.0,
+                params,
+            )
+        });
+    }
+
+    // runs the simulation, if there is one, as far as the clock has come
+    fn step_physics(&mut self, clock: &TextureClock) {
+        if self.physics.is_none() {
+            return;
+        }
+        let (restart, frames) = self.physics_progress.advance(clock);
+        if restart {
+            // the knocks it took on the way are forgotten
+            self.restart_physics();
+        }
+        if let Some(world) = self.physics.as_mut() {
+            for _ in 0..frames {
+                world.step(1.0 / NOMINAL_FPS);
+            }
+        }
+    }
+
+    // takes on another descriptor's look and an already packed layout for it, keeping its name,
+    // layer and size
+    pub fn become_layout(
+        &mut self,
+        desc: &RenderToTextureDescriptor,
+        allcircs: AllCircles,
+        now: f32,
+    ) {
+        self.start_color = desc.start_color;
+        self.background_color = desc.background_color;
+        self.color = desc.color;
+        self.filters = desc.filters;
+        self.color_filters = ColorFilters::new(desc.filters);
+        self.animation = desc.animation;
+        // until the new descriptor's formula sheet loads
+        self.formulas = Vec::new();
+        self.reveal = None;
+        self.physics_params = physics_params(desc);
+        self.allcircs = allcircs;
+        self.reset_physics(now);
+        // wear was per circle of the old layout
+        self.reactions = Reactions::new(self.allcircs.r.len());
+    }
+}
+
+fn physics_params(desc: &RenderToTextureDescriptor) -> Option<PhysicsParams> {
+    match desc.params {
+        GeneratorParams::Physics(params) => Some(params),
+        _ => None,
+    }
 }
 
 pub fn circles2_add_circles_to_layer(
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
+    circle_mesh: Res<CircleMesh>,
     mut materials: ResMut<Assets<ColorMaterial>>,
-    mut query: Query<&mut Circles2>,
+    mut images: ResMut<Assets<Image>>,
+    mut dyntex: ResMut<DynamicTextures>,
+    mut query: Query<
+        (
+            Entity,
+            &mut Circles2,
+            &TextureClock,
+            Option<&TexturePalette>,
+        ),
+        Without<PendingPalette>,
+    >,
 ) {
     if query.is_empty() {
         return;
     }
-    for mut circles2 in &mut query {
+    for (owner, mut circles2, clock, palette) in &mut query {
         if circles2.done_setup {
             continue;
         }
         let first_pass_layer = RenderLayers::layer(circles2.layer);
-        let window_width = 1280; //windows.primary().physical_width();
-
-        let mut r = 20.0;
-
         let mut rng = rand::thread_rng();
-
-        let (mut generator, mut current_color) = color_generator::ColorGenerator::new(
-            circles2.start_color.hue,
-            circles2.start_color.saturation,
-            circles2.start_color.lightness,
+        let (allcircs, generator) = pack_circles(
+            &circles2.start_color,
+            circles2.color,
+            palette.map(|p| &p.0),
+            Some(circles2.background_color),
+            circles2.size,
+            &mut rng,
         );
+        report_adjusted(circles2.name, &generator);
+        circles2.allcircs = allcircs;
+        circles2.reset_physics(clock.elapsed());
+        circles2.reactions = Reactions::new(circles2.allcircs.r.len());
+        circles2.reveal_start = clock.elapsed();
+        // circles being revealed start at nothing
+        let initial_scale = if circles2.reveal.is_some() { 0.0 } else { 1.0 };
 
-        let mut color_change_count = 0;
-        let mut circles_of_this_radius: u32 = 0;
-
-        loop {
-            let mut success: bool = false;
-            for _ in 1..=100 {
-                // take many chances to fit this circle in
-                let npos: Vec2 = Vec2::new(
-                    rng.gen::<f32>() * (window_width as f32 - r * 2.0) + r
-                        - window_width as f32 / 2.0,
-                    rng.gen::<f32>() * (window_width as f32 - r * 2.0) + r
-                        - window_width as f32 / 2.0,
-                );
-                if !intersects_any2(npos, r, &circles2.allcircs.pos, &circles2.allcircs.r) {
-                    circles2.allcircs.pos.push(npos);
-                    circles2.allcircs.r.push(r);
-                    circles2.allcircs.c.push(current_color);
-                    success = true;
-                    circles_of_this_radius += 1;
-                    break;
-                }
-            }
-            // if failure, decrease radius and loop if not <= min_radius
-            if !success || circles_of_this_radius >= MAX_CIRCLES_PER_RADIUS {
-                circles_of_this_radius = 0;
-                r -= 1.0;
-                if r <= MIN_RADIUS {
-                    break;
-                }
-            } else {
-                // if success, might change color's hue
-                color_change_count += 1;
-                if color_change_count >= 30 {
-                    // every 30 circles, change color
-                    color_change_count = 0;
-                    current_color = generator.rand_color(&mut rng);
-                } else {
-                    current_color = generator.rand_color_variation(&mut rng);
-                }
-            }
-        }
+        let canvas = circles_canvas(
+            &circles2.allcircs,
+            circles2.size,
+            circles2.background_color,
+            circles2.filters,
+        );
+        circles2.show_canvas_if_needed(&mut commands, &mut images, &canvas);
+        // a snapshot for textures that use this one as an input
+        dyntex.publish_canvas(circles2.name, canvas);
 
-        for (pos, (r, c)) in circles2
+        for (index, (pos, (r, c))) in circles2
             .allcircs
             .pos
             .iter()
             .zip(circles2.allcircs.r.iter().zip(circles2.allcircs.c.iter()))
+            .enumerate()
         {
             commands
                 .spawn_bundle(MaterialMesh2dBundle {
-                    mesh: meshes.add(shape::Circle::new(*r).into()).into(),
-                    material: materials.add(ColorMaterial::from(*c)),
-                    transform: Transform::from_translation(Vec3::new(pos.x, pos.y, 0.0)),
+                    mesh: circle_mesh.0.clone().into(),
+                    material: materials.add(ColorMaterial::from(circles2.color_filters.apply(
+                        *c,
+                        *pos,
+                        circles2.size,
+                    ))),
+                    transform: Transform::from_translation(Vec3::new(pos.x, pos.y, 0.0))
+                        .with_scale(Vec3::splat(*r * initial_scale)),
+                    visibility: Visibility {
+                        is_visible: circles2.image.is_none(),
+                    },
                     ..default()
                 })
-                .insert(first_pass_layer);
+                .insert(first_pass_layer)
+                .insert(TextureCircle { owner, index });
         }
         circles2.done_setup = true;
     }
 }
 
+// how far apart two circle edges can be and still count as next to each other
+const NEIGHBOUR_GAP: f32 = 10.0;
+
+// the colors a new circle has to contrast with: the background and the circles next to it
+fn contrast_neighbours(
+    pos: Vec2,
+    r: f32,
+    circles: impl Iterator<Item = (Vec2, f32, Color)>,
+    background: Option<Color>,
+) -> Vec<Color> {
+    background
+        .into_iter()
+        .chain(
+            circles
+                .filter(|(p, pr, _)| p.distance(pos) < r + pr + NEIGHBOUR_GAP)
+                .map(|(_, _, c)| c),
+        )
+        .collect()
+}
+
+// the color options' mapping for a circle at pos in a texture size pixels across
+fn mapped_color(
+    generator: &ColorGenerator,
+    options: ColorOptions,
+    (pos, r): (Vec2, f32),
+    size: u32,
+    sequential: Color,
+) -> Color {
+    options
+        .mapping
+        .t(pos, r, size as f32 / 2.0, (MIN_RADIUS, MAX_RADIUS))
+        .map_or(sequential, |t| generator.shade(t))
+}
+
+pub fn report_adjusted(name: &str, generator: &ColorGenerator) {
+    if generator.adjusted_count() > 0 {
+        info!(
+            "{}: adjusted {} colors to keep the minimum contrast",
+            name,
+            generator.adjusted_count()
+        );
+    }
+}
+
+// packs circles of decreasing radius into the area, shifting their color as it goes; color
+// mappings are laid over the middle of it, a texture size pixels across
+pub fn pack_circles(
+    start_color: &StartColor,
+    color: ColorOptions,
+    palette: Option<&ColorPalette>,
+    background: Option<Color>,
+    size: u32,
+    rng: &mut impl Rng,
+) -> (AllCircles, ColorGenerator) {
+    let mut allcircs = AllCircles::new();
+    let window_width = 1280; //windows.primary().physical_width();
+
+    let mut r = MAX_RADIUS;
+
+    let (mut generator, mut current_color) =
+        ColorGenerator::from_start_color(start_color, color, palette);
+
+    let mut color_change_count = 0;
+    let mut circles_of_this_radius: u32 = 0;
+
+    loop {
+        let mut success: bool = false;
+        for _ in 1..=100 {
+            // take many chances to fit this circle in
+            let npos: Vec2 = Vec2::new(
+                rng.gen::<f32>() * (window_width as f32 - r * 2.0) + r - window_width as f32 / 2.0,
+                rng.gen::<f32>() * (window_width as f32 - r * 2.0) + r - window_width as f32 / 2.0,
+            );
+            if !intersects_any2(npos, r, &allcircs.pos, &allcircs.r) {
+                let others = contrast_neighbours(
+                    npos,
+                    r,
+                    allcircs
+                        .pos
+                        .iter()
+                        .zip(&allcircs.r)
+                        .zip(&allcircs.c)
+                        .map(|((p, r), c)| (*p, *r, *c)),
+                    background,
+                );
+                allcircs.pos.push(npos);
+                allcircs.r.push(r);
+                let c = mapped_color(&generator, color, (npos, r), size, current_color);
+                allcircs.c.push(generator.constrain(c, &others));
+                success = true;
+                circles_of_this_radius += 1;
+                break;
+            }
+        }
+        // if failure, decrease radius and loop if not <= min_radius
+        if !success || circles_of_this_radius >= MAX_CIRCLES_PER_RADIUS {
+            circles_of_this_radius = 0;
+            r -= 1.0;
+            if r <= MIN_RADIUS {
+                break;
+            }
+        } else {
+            // if success, might change color's hue
+            color_change_count += 1;
+            if color_change_count >= 30 {
+                // every 30 circles, change color
+                color_change_count = 0;
+                current_color = generator.rand_color(rng);
+            } else {
+                current_color = generator.rand_color_variation(rng);
+            }
+        }
+    }
+    (allcircs, generator)
+}
+
+// draws a freshly packed set of circles onto the canvas, whose center is the origin of the packing
+// area; there's no background to keep contrast with, only the neighbouring circles. Returns how
+// many colors were adjusted for contrast
+pub fn render_packed_circles(
+    start_color: &StartColor,
+    color: ColorOptions,
+    palette: Option<&ColorPalette>,
+    canvas: &mut Canvas,
+    rng: &mut impl Rng,
+) -> usize {
+    let (allcircs, generator) = pack_circles(start_color, color, palette, None, canvas.width, rng);
+    rasterize_circles(&allcircs, canvas);
+    generator.adjusted_count()
+}
+
+// the circles drawn over the background with the whole filter chain, the way the CPU generators
+// make their canvases
+pub fn circles_canvas(
+    allcircs: &AllCircles,
+    size: u32,
+    background: Color,
+    filters: &[Filter],
+) -> Canvas {
+    let mut canvas = Canvas::new(size, size, background.as_rgba_f32());
+    rasterize_circles(allcircs, &mut canvas);
+    apply_filters(&mut canvas, filters);
+    canvas
+}
+
+// one circle as circles2_update last drew it: placement index, position, radius and color before
+// the per-color filters
+type FrameCircle = (usize, Vec2, f32, Color);
+
+// draws this frame's circles for textures shown through a canvas sprite, and hands them to the
+// textures that use them as inputs
+fn draw_frames(
+    frames: HashMap<Entity, Vec<FrameCircle>>,
+    query: &Query<(Entity, &mut Circles2, &TextureClock, Option<&Morph>)>,
+    images: &mut Assets<Image>,
+    dyntex: &mut DynamicTextures,
+) {
+    for (owner, mut frame) in frames {
+        let Ok((_, circles2, clock, morph)) = query.get(owner) else {
+            continue;
+        };
+        frame.sort_by_key(|(index, ..)| *index);
+        let circles = AllCircles {
+            pos: frame.iter().map(|f| f.1).collect(),
+            r: frame.iter().map(|f| f.2).collect(),
+            c: frame.iter().map(|f| f.3).collect(),
+        };
+        let background = morph.map_or(circles2.background_color, |morph| {
+            morph.background(clock.elapsed())
+        });
+        let canvas = circles_canvas(&circles, circles2.size, background, circles2.filters);
+        if let Some(image) = &circles2.image {
+            upload_canvas(images, image, &canvas);
+        }
+        if dyntex.wants_canvas(circles2.name, circles2.filters) {
+            dyntex.publish_canvas(circles2.name, canvas);
+        }
+    }
+}
+
+// CPU rendering of the circles, matching what the render-to-texture camera sees
+pub fn rasterize_circles(allcircs: &AllCircles, canvas: &mut Canvas) {
+    let (half_w, half_h) = (canvas.width as f32 / 2.0, canvas.height as f32 / 2.0);
+    for (pos, (r, c)) in allcircs
+        .pos
+        .iter()
+        .zip(allcircs.r.iter().zip(allcircs.c.iter()))
+    {
+        // world y points up, canvas rows go down
+        let (cx, cy) = (pos.x + half_w, half_h - pos.y);
+        let x0 = (cx - r).floor().max(0.0) as u32;
+        let x1 = ((cx + r).ceil().max(0.0) as u32).min(canvas.width);
+        let y0 = (cy - r).floor().max(0.0) as u32;
+        let y1 = ((cy + r).ceil().max(0.0) as u32).min(canvas.height);
+        let color = c.as_rgba_f32();
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let d = Vec2::new(x as f32 + 0.5 - cx, y as f32 + 0.5 - cy).length();
+                // antialiased edge
+                let coverage = (r - d + 0.5).clamp(0.0, 1.0);
+                if coverage > 0.0 {
+                    canvas.paint(x, y, color, coverage);
+                }
+            }
+        }
+    }
+}
+
+pub fn restart_reveals(
+    mut events: EventReader<RestartReveal>,
+    mut query: Query<(&mut Circles2, &TextureClock), Without<Morph>>,
+) {
+    for e in events.iter() {
+        let Some((mut circles2, clock)) = query
+            .iter_mut()
+            .find(|(circles2, _)| circles2.name == e.name)
+        else {
+            warn!(
+                "not restarting the reveal of {}: no Circles2 texture of that name that isn't morphing",
+                e.name
+            );
+            continue;
+        };
+        let Some(reveal) = e.reveal.or(circles2.reveal) else {
+            warn!("not restarting the reveal of {}: it has none", e.name);
+            continue;
+        };
+        circles2.reveal = Some(reveal);
+        circles2.reveal_start = clock.elapsed();
+        circles2.revealed = false;
+    }
+}
+
+fn finish_reveal(
+    circles2: &mut Circles2,
+    clock: &TextureClock,
+    revealed: &mut EventWriter<RevealFinished>,
+) {
+    if let Some(reveal) = circles2.reveal {
+        if circles2.done_setup
+            && !circles2.revealed
+            && reveal.finished(clock.elapsed() - circles2.reveal_start)
+        {
+            circles2.revealed = true;
+            revealed.send(RevealFinished {
+                name: circles2.name,
+            });
+        }
+    }
+}
+
+// a system's resources are its arguments, and this one draws on a lot of them
+#[allow(clippy::too_many_arguments)]
 pub fn circles2_update(
     time: Res<Time>,
-    mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
-    mut query: Query<&mut Circles2>,
-    mut query2: Query<(&mut Mesh2dHandle, &mut Transform)>,
-    //    mut query3: Query<&mut ColorMaterial>,
+    mut images: ResMut<Assets<Image>>,
+    mut dyntex: ResMut<DynamicTextures>,
+    mut query: Query<(Entity, &mut Circles2, &TextureClock, Option<&Morph>)>,
+    mut circles: Query<(
+        &TextureCircle,
+        &mut Transform,
+        &Handle<ColorMaterial>,
+        &mut Visibility,
+    )>,
+    motion: Res<MotionSettings>,
+    audio: Res<AudioInput>,
+    mut flashes: ResMut<FlashAnalyzer>,
+    mut revealed: EventWriter<RevealFinished>,
 ) {
-    if query.is_empty() || query2.is_empty() {
+    if query.is_empty() || circles.is_empty() {
         return;
     }
-    let t = time.time_since_startup().as_secs_f32();
-    for circles2 in &mut query {
-        if !circles2.done_setup {
+    // simulation steps at a fixed rate, so a texture's clock always gives the same motion
+    for (_, mut circles2, clock, _) in &mut query {
+        circles2.step_physics(clock);
+    }
+    // this frame's circles of each texture drawn on the CPU or used as an input
+    let mut frames: HashMap<Entity, Vec<FrameCircle>> = query
+        .iter()
+        .filter(|(_, circles2, clock, _)| {
+            circles2.needs_redraw(clock)
+                && (circles2.image.is_some()
+                    || dyntex.wants_canvas(circles2.name, circles2.filters))
+        })
+        .map(|(owner, ..)| (owner, Vec::new()))
+        .collect();
+    // area-weighted luminance of each texture's circles, for the flash analyzer
+    let mut lit: HashMap<Entity, (f32, f32)> = HashMap::default();
+    for (circle, mut tr, material, mut visibility) in &mut circles {
+        let Ok((_, circles2, clock, morph)) = query.get(circle.owner) else {
+            continue;
+        };
+        if !circles2.needs_redraw(clock) {
             continue;
         }
-        for (r, (mut m, _)) in circles2.allcircs.r.iter().zip(&mut query2) {
-            m.0 = meshes.add(shape::Circle::new(*r * (1.0 + 0.12 * (10.0 * *r * t).sin())).into());
+        // circles that joined in a morph follow the texture over to its canvas sprite
+        if visibility.is_visible != circles2.image.is_none() {
+            visibility.is_visible = circles2.image.is_none();
         }
-        for (p, (_, mut tr)) in circles2.allcircs.pos.iter().zip(&mut query2) {
-            *tr = Transform::from_translation(Vec3::new(
-                p.x + 5.0 * (0.7 * p.x * t).tan().abs().clamp(0.0, 1.0),
-                p.y + 3.0 * (3.1 * p.y * t).sin().abs().clamp(0.0, 1.0),
-                0.0,
-            ));
+        let t = clock.elapsed();
+        let all = &circles2.allcircs;
+        let (r, p, c) = match morph {
+            Some(morph) => morph.circle(circle.index, t),
+            None => (
+                all.r[circle.index],
+                circles2
+                    .physics
+                    .as_ref()
+                    .map_or(all.pos[circle.index], |world| world.pos[circle.index]),
+                all.c[circle.index],
+            ),
+        };
+
+        let mut modulation = modulate(
+            circles2.animation,
+            t,
+            CircleInfo {
+                index: circle.index,
+                radius: r,
+                pos: p,
+            },
+            &motion,
+            &audio.features,
+        );
+        let (radius, pos) =
+            circles2.apply_formulas(t, circle.index, (r, p, c), &audio.features, &mut modulation);
+        tr.translation = (pos + modulation.offset).extend(0.0);
+        let grown = circles2.reveal.map_or(1.0, |reveal| {
+            reveal.scale(t - circles2.reveal_start, circle.index, all.r.len())
+        });
+        let grown = grown * circles2.reactions.presence(circle.index);
+        tr.scale = Vec3::splat((radius * (1.0 + modulation.radius) * grown).max(0.0));
+
+        let tinted = circles2
+            .reactions
+            .tint(animate_color(c, &modulation, circles2.color.space), &motion);
+        let color = circles2.color_filters.apply(tinted, p, circles2.size);
+        if let Some(material) = materials.get_mut(material) {
+            material.color = color;
+        }
+        if let Some(frame) = frames.get_mut(&circle.owner) {
+            frame.push((circle.index, tr.translation.truncate(), tr.scale.x, tinted));
         }
-        for (c, m) in circles2.allcircs.c.iter().zip(materials.iter_mut()) {
-            let mut hsl = Hsl::from_color(Rgb::new(c.r(), c.g(), c.b()));
-            hsl.saturation = num::clamp(hsl.saturation + 0.4 * (3.0 * t).sin(), 0.0, 1.0);
-            hsl.lightness = num::clamp(hsl.lightness + 0.4 * (5.0 * t).sin(), 0.3, 0.9);
-            let c_srgb = Srgb::from_color(hsl);
-            m.1.color = Color::rgba(c_srgb.red, c_srgb.green, c_srgb.blue, 1.0);
+        let area = visible_area(tr.translation.truncate(), tr.scale.x, circles2.size);
+        let (lit_area, covered) = lit.entry(circle.owner).or_default();
+        *lit_area += area * relative_luminance(color.as_rgba_f32());
+        *covered += area;
+    }
+
+    // the flash guideline is about real seconds, whatever the textures' clocks are doing
+    let now = time.seconds_since_startup() as f32;
+    for (owner, (lit_area, covered)) in lit {
+        let Ok((_, circles2, clock, morph)) = query.get(owner) else {
+            continue;
+        };
+        // measure_canvas_flashes samples the canvas this frame is about to publish
+        if !(frames.contains_key(&owner) && dyntex.wants_canvas(circles2.name, circles2.filters)) {
+            let background = morph.map_or(circles2.background_color, |morph| {
+                morph.background(clock.elapsed())
+            });
+            flashes.record(
+                circles2.name,
+                now,
+                circles2.luminance(lit_area, covered, background),
+            );
         }
-        // camera2dbundle.camera_2d.clear_color = ClearColorConfig::Custom(background_color)
     }
+    draw_frames(frames, &query, &mut images, &mut dyntex);
+    for (_, mut circles2, clock, _) in &mut query {
+        finish_reveal(&mut circles2, clock, &mut revealed);
+    }
+    // camera2dbundle.camera_2d.clear_color = ClearColorConfig::Custom(background_color)
+}
+
+// roughly how much of a circle shows in a texture size pixels across, centered on the origin
+fn visible_area(pos: Vec2, r: f32, size: u32) -> f32 {
+    let half = size as f32 / 2.0;
+    // the share of the circle's bounding box inside the texture, along each axis
+    let inside =
+        |c: f32| ((c + r).min(half) - (c - r).max(-half)).max(0.0) / (2.0 * r).max(f32::EPSILON);
+    std::f32::consts::PI * r * r * inside(pos.x) * inside(pos.y)
 }
 
 fn intersects_any2(pos: Vec2, r: f32, vec_pos: &[Vec2], vec_radius: &[f32]) -> bool {
@@ -341,3 +1128,34 @@ fn intersects_any2(pos: Vec2, r: f32, vec_pos: &[Vec2], vec_radius: &[f32]) -> b
     }
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shifts(direction: CycleDirection, len: usize) -> Vec<usize> {
+        let cycle = PaletteCycle {
+            direction,
+            ..PaletteCycle::DEFAULT
+        };
+        (0..8)
+            .map(|step| cycle.shift(step as f32 + 0.5, len))
+            .collect()
+    }
+
+    #[test]
+    fn cycles_forward_backward_and_back_and_forth() {
+        assert_eq!(
+            shifts(CycleDirection::Forward, 3),
+            vec![0, 1, 2, 0, 1, 2, 0, 1]
+        );
+        assert_eq!(
+            shifts(CycleDirection::Backward, 3),
+            vec![0, 2, 1, 0, 2, 1, 0, 2]
+        );
+        assert_eq!(
+            shifts(CycleDirection::PingPong, 3),
+            vec![0, 1, 2, 1, 0, 1, 2, 1]
+        );
+    }
+}
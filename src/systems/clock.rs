@@ -0,0 +1,279 @@
+use bevy::ecs::{
+    component::Component,
+    event::EventReader,
+    system::{Query, Res},
+};
+use bevy::time::Time;
+
+// the frame rate simulations were tuned at; they run this many steps per second of clock time
+pub const NOMINAL_FPS: f32 = 60.0;
+// most simulation steps one real-time frame can owe, times the time scale; a long hitch is
+// dropped rather than caught up on
+const MAX_FRAMES_PER_TICK: u32 = 4;
+
+// each dynamic texture's own animation time, which can be paused, slowed, sped up and moved
+#[derive(Component)]
+pub struct TextureClock {
+    pub name: &'static str,
+    pub paused: bool,
+    // 1.0 is real time
+    pub time_scale: f32,
+    // when set, every frame advances exactly this many seconds (times time_scale), however long
+    // the frame really took, so the same frames come out every run
+    pub fixed_step: Option<f32>,
+    elapsed: f32,
+    delta: f32,
+    // simulation frames owed but not yet run
+    frame_debt: f32,
+    frames: u32,
+    seeked: bool,
+}
+
+impl TextureClock {
+    pub fn new(name: &'static str) -> TextureClock {
+        TextureClock {
+            name,
+            paused: false,
+            time_scale: 1.0,
+            fixed_step: None,
+            elapsed: 0.0,
+            delta: 0.0,
+            frame_debt: 0.0,
+            frames: 0,
+            seeked: false,
+        }
+    }
+
+    // seconds of animation time so far
+    pub fn elapsed(&self) -> f32 {
+        self.elapsed
+    }
+
+    // how far this frame moved the clock
+    pub fn delta(&self) -> f32 {
+        self.delta
+    }
+
+    // whole simulation steps' worth of time this frame, at NOMINAL_FPS
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    // whether anything time-based needs redrawing this frame
+    pub fn changed(&self) -> bool {
+        self.delta != 0.0 || self.seeked
+    }
+
+    // whether the clock jumped this frame
+    pub fn seeked(&self) -> bool {
+        self.seeked
+    }
+
+    // jumps straight to a time; animation and simulations follow
+    pub fn seek(&mut self, seconds: f32) {
+        self.elapsed = seconds.max(0.0);
+        self.seeked = true;
+    }
+
+    pub fn tick(&mut self, real_delta: f32) {
+        self.seeked = false;
+        self.delta = if self.paused {
+            0.0
+        } else {
+            self.fixed_step.unwrap_or(real_delta) * self.time_scale.max(0.0)
+        };
+        self.elapsed += self.delta;
+        self.frame_debt += self.delta * NOMINAL_FPS;
+        let owed = self.frame_debt.floor() as u32;
+        self.frame_debt -= owed as f32;
+        // fixed steps never hitch, and a sped-up clock owes more every frame
+        self.frames = if self.fixed_step.is_some() {
+            owed
+        } else {
+            owed.min(self.max_frames())
+        };
+    }
+
+    // the most simulation steps one frame runs, outside fixed steps
+    fn max_frames(&self) -> u32 {
+        (MAX_FRAMES_PER_TICK as f32 * self.time_scale.max(1.0)).ceil() as u32
+    }
+
+    pub fn apply(&mut self, command: ClockCommand) {
+        match command {
+            ClockCommand::Pause => self.paused = true,
+            ClockCommand::Resume => self.paused = false,
+            ClockCommand::TogglePause => self.paused = !self.paused,
+            ClockCommand::SetTimeScale(scale) => self.time_scale = scale,
+            ClockCommand::Seek(seconds) => self.seek(seconds),
+            ClockCommand::SetFixedStep(step) => self.fixed_step = step,
+        }
+    }
+}
+
+// how far a simulation driven by a texture's clock has got: the clock time it started at, the
+// frames it has run since, and the frames a seek still has it catching up on
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SimulationProgress {
+    start: f32,
+    frames: u32,
+    seek_owed: u32,
+}
+
+impl SimulationProgress {
+    pub fn new(start: f32) -> SimulationProgress {
+        SimulationProgress {
+            start,
+            frames: 0,
+            seek_owed: 0,
+        }
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    // whether the simulation has to start over this frame, and how many frames to run. After a
+    // seek it catches up to where it would be had it run all along, starting over to go back, no
+    // more than a frame's worth of steps at a time so a long seek doesn't stall the game
+    pub fn advance(&mut self, clock: &TextureClock) -> (bool, u32) {
+        let mut restart = false;
+        let mut frames = clock.frames;
+        if clock.seeked {
+            let target = ((clock.elapsed - self.start).max(0.0) * NOMINAL_FPS).round() as u32;
+            if target < self.frames {
+                restart = true;
+                self.frames = 0;
+            }
+            self.seek_owed = target - self.frames;
+            frames = 0;
+        }
+        let catch_up = self.seek_owed.min(clock.max_frames());
+        self.seek_owed -= catch_up;
+        frames += catch_up;
+        self.frames += frames;
+        (restart, frames)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockCommand {
+    Pause,
+    Resume,
+    TogglePause,
+    SetTimeScale(f32),
+    Seek(f32),
+    SetFixedStep(Option<f32>),
+}
+
+// sends a command to one dynamic texture's clock, or to all of them when name is None
+pub struct TextureClockEvent {
+    pub name: Option<String>,
+    pub command: ClockCommand,
+}
+
+// advances every clock, then applies the frame's commands so a seek lands exactly where it's told
+pub fn tick_texture_clocks(
+    time: Res<Time>,
+    mut events: EventReader<TextureClockEvent>,
+    mut clocks: Query<&mut TextureClock>,
+) {
+    for mut clock in &mut clocks {
+        clock.tick(time.delta_seconds());
+    }
+    for e in events.iter() {
+        for mut clock in &mut clocks {
+            if e.name.as_deref().is_none_or(|name| name == clock.name) {
+                clock.apply(e.command);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_at_its_time_scale() {
+        let mut clock = TextureClock::new("test");
+        clock.time_scale = 0.5;
+        clock.tick(0.1);
+        assert!((clock.elapsed() - 0.05).abs() < 1e-6);
+        assert!(clock.changed());
+        clock.apply(ClockCommand::Pause);
+        clock.tick(0.1);
+        assert!((clock.elapsed() - 0.05).abs() < 1e-6);
+        assert_eq!(clock.frames(), 0);
+        assert!(!clock.changed());
+    }
+
+    #[test]
+    fn fixed_steps_owe_whole_frames() {
+        let mut clock = TextureClock::new("test");
+        clock.fixed_step = Some(1.0 / 120.0);
+        let frames: Vec<u32> = (0..4)
+            .map(|_| {
+                clock.tick(1.0);
+                clock.frames()
+            })
+            .collect();
+        assert_eq!(frames, vec![0, 1, 0, 1]);
+        // fixed steps aren't capped
+        clock.fixed_step = Some(1.0);
+        clock.tick(0.0);
+        assert_eq!(clock.frames(), 60);
+    }
+
+    #[test]
+    fn a_hitch_is_dropped_but_a_sped_up_clock_keeps_up() {
+        let mut clock = TextureClock::new("test");
+        clock.tick(1.0);
+        assert_eq!(clock.frames(), MAX_FRAMES_PER_TICK);
+        clock.time_scale = 3.0;
+        clock.tick(1.0 / NOMINAL_FPS);
+        assert_eq!(clock.frames(), 3);
+    }
+
+    #[test]
+    fn seeking_catches_simulations_up_a_frame_at_a_time() {
+        let mut clock = TextureClock::new("test");
+        let mut progress = SimulationProgress::new(0.0);
+        clock.tick(1.0 / NOMINAL_FPS);
+        assert!(!clock.seeked());
+        assert_eq!(progress.advance(&clock), (false, 1));
+
+        clock.seek(2.0);
+        assert!(clock.seeked() && clock.changed());
+        assert!((clock.elapsed() - 2.0).abs() < 1e-6);
+        assert_eq!(progress.advance(&clock), (false, MAX_FRAMES_PER_TICK));
+        // the rest of the 120 frames owed at 2s come over the next frames
+        let mut ticks = 0;
+        while progress.frames() < 120 {
+            clock.tick(0.0);
+            assert!(progress.advance(&clock).1 <= MAX_FRAMES_PER_TICK);
+            ticks += 1;
+        }
+        assert_eq!(ticks, 29);
+        assert_eq!(progress.frames(), 120);
+        clock.tick(0.0);
+        assert_eq!(progress.advance(&clock), (false, 0));
+
+        // going back starts over
+        clock.seek(0.5);
+        assert_eq!(progress.advance(&clock), (true, MAX_FRAMES_PER_TICK));
+        assert_eq!(progress.frames(), MAX_FRAMES_PER_TICK);
+
+        // a simulation started later owes less
+        clock.seek(2.0);
+        let mut later = SimulationProgress::new(1.9);
+        assert_eq!(later.advance(&clock), (false, MAX_FRAMES_PER_TICK));
+        clock.tick(0.0);
+        assert_eq!(later.advance(&clock), (false, 2));
+
+        clock.seek(-1.0);
+        assert!(clock.elapsed().abs() < 1e-6);
+        clock.tick(0.0);
+        assert!(!clock.seeked());
+    }
+}
@@ -1,35 +1,448 @@
+use bevy::math::Vec2;
 use bevy::render::color::Color;
 use rand::Rng;
 
-use palette::{FromColor, Hsl /*, Srgb */};
+use palette::convert::FromColorUnclamped;
+use palette::{Clamp, FromColor, Hsl, Oklab, OklabHue, Oklch, RelativeContrast, Srgb};
+
+use crate::systems::animation::Modulation;
+use crate::systems::color_palette::{ColorPalette, PaletteMode};
+use crate::systems::dynamic_textures::StartColor;
+
+// how new hues are picked relative to the start hue
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HueStrategy {
+    // the start hue and its neighbours 30 degrees either side
+    #[default]
+    Analogous,
+    Complementary,
+    // the start hue and the two hues either side of its complement
+    SplitComplementary,
+    Triadic,
+    Tetradic,
+    // only the start hue
+    Monochrome,
+}
+
+impl HueStrategy {
+    // offsets in degrees from the start hue of the hues this strategy picks from
+    pub fn hue_offsets(self) -> &'static [f32] {
+        match self {
+            HueStrategy::Analogous => &[0.0, -30.0, 30.0],
+            HueStrategy::Complementary => &[0.0, 180.0],
+            HueStrategy::SplitComplementary => &[0.0, 150.0, 210.0],
+            HueStrategy::Triadic => &[0.0, 120.0, 240.0],
+            HueStrategy::Tetradic => &[0.0, 90.0, 180.0, 270.0],
+            HueStrategy::Monochrome => &[0.0],
+        }
+    }
+}
+
+// the space colors are varied and animated in
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorSpace {
+    #[default]
+    Hsl,
+    // equal steps look equally far apart whatever the hue; out of gamut colors lose chroma
+    Oklch,
+}
+
+// Oklab lightness the Oklch variations stay within, matching the HSL mode's 0.3..0.9
+pub const OKLCH_LIGHTNESS: (f32, f32) = (0.45, 0.9);
+// most chroma any sRGB color has
+pub const OKLCH_MAX_CHROMA: f32 = 0.33;
+
+// how far apart two colors have to be
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MinContrast {
+    // WCAG contrast ratio, 1.0..=21.0; 3.0 is the WCAG minimum for graphics
+    Wcag(f32),
+    // Euclidean distance in Oklab; about 0.02 is just noticeable
+    DeltaE(f32),
+}
+
+impl MinContrast {
+    fn contrast(self, a: Color, b: Color) -> f32 {
+        let (a, b) = (
+            Srgb::new(a.r(), a.g(), a.b()),
+            Srgb::new(b.r(), b.g(), b.b()),
+        );
+        match self {
+            MinContrast::Wcag(_) => a.get_contrast_ratio(&b),
+            MinContrast::DeltaE(_) => {
+                let (a, b) = (Oklab::from_color(a), Oklab::from_color(b));
+                ((a.l - b.l).powi(2) + (a.a - b.a).powi(2) + (a.b - b.b).powi(2)).sqrt()
+            }
+        }
+    }
+
+    fn minimum(self) -> f32 {
+        match self {
+            MinContrast::Wcag(m) | MinContrast::DeltaE(m) => m,
+        }
+    }
+
+    pub fn is_met(self, a: Color, b: Color) -> bool {
+        self.contrast(a, b) >= self.minimum()
+    }
+}
+
+// where a circle's color comes from; every mode but Sequence picks a shade of the start color, or
+// of the palette from darkest to lightest, for a t in 0.0..=1.0
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub enum ColorMapping {
+    // the order circles are placed in
+    #[default]
+    Sequence,
+    // dark to light along the direction angle degrees counterclockwise from +x
+    Linear {
+        angle: f32,
+    },
+    // light at center, dark at the edges; center is relative to the texture's middle, in units of
+    // half its width, so (1, 1) is the top right corner
+    Radial {
+        center: Vec2,
+    },
+    // dark to light counterclockwise around center, in the same units as Radial's, starting at +x
+    Angular {
+        center: Vec2,
+    },
+    // big circles dark, small ones light
+    Radius,
+    // light at point, in world units from the texture's center, fading to dark at radius
+    Focus {
+        point: Vec2,
+        radius: f32,
+    },
+}
+
+impl ColorMapping {
+    // t for a circle at pos, in world units within a texture half_extent wide either side of its
+    // center, with a radius from radius_range; None for Sequence
+    pub fn t(self, pos: Vec2, r: f32, half_extent: f32, radius_range: (f32, f32)) -> Option<f32> {
+        let rel = pos / half_extent.max(f32::EPSILON);
+        let t = match self {
+            ColorMapping::Sequence => return None,
+            ColorMapping::Linear { angle } => {
+                let (sin, cos) = angle.to_radians().sin_cos();
+                0.5 + 0.5 * rel.dot(Vec2::new(cos, sin))
+            }
+            ColorMapping::Radial { center } => {
+                1.0 - rel.distance(center) / std::f32::consts::SQRT_2
+            }
+            ColorMapping::Angular { center } => {
+                let d = rel - center;
+                d.y.atan2(d.x).rem_euclid(std::f32::consts::TAU) / std::f32::consts::TAU
+            }
+            ColorMapping::Radius => {
+                let (min, max) = radius_range;
+                1.0 - (r - min) / (max - min).max(f32::EPSILON)
+            }
+            ColorMapping::Focus { point, radius } => {
+                1.0 - pos.distance(point) / radius.max(f32::EPSILON)
+            }
+        };
+        Some(num::clamp(t, 0.0, 1.0))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ColorOptions {
+    pub space: ColorSpace,
+    pub strategy: HueStrategy,
+    // how far in degrees a picked hue may wander from the strategy's hue
+    pub hue_spread: f32,
+    // asset path of a palette to draw every color from instead of varying HSL values
+    pub palette: Option<&'static str>,
+    pub palette_mode: PaletteMode,
+    // contrast every color must have against the background and the colors next to it
+    pub min_contrast: Option<MinContrast>,
+    pub mapping: ColorMapping,
+}
+
+impl ColorOptions {
+    pub const DEFAULT: ColorOptions = ColorOptions {
+        space: ColorSpace::Hsl,
+        strategy: HueStrategy::Analogous,
+        hue_spread: 15.0,
+        palette: None,
+        palette_mode: PaletteMode::Uniform,
+        min_contrast: None,
+        mapping: ColorMapping::Sequence,
+    };
+}
+
+impl Default for ColorOptions {
+    fn default() -> Self {
+        ColorOptions::DEFAULT
+    }
+}
 
 pub struct ColorGenerator {
     hsl: palette::Hsl,
+    oklch: Oklch,
+    // in degrees
+    start_hue: f32,
+    // the start color's hue in Oklch, in degrees
+    start_oklch_hue: f32,
+    options: ColorOptions,
+    palette: Option<ColorPalette>,
+    // palette entry indices from darkest to lightest
+    palette_by_lightness: Vec<usize>,
+    palette_index: usize,
+    // how many colors constrain() had to change
+    adjusted: usize,
 }
 
 impl ColorGenerator {
+    // hue is a fraction of a full turn, as in StartColor
     pub fn new(hue: f32, saturation: f32, lightness: f32) -> (ColorGenerator, Color) {
-        let hsl = Hsl::new(hue, saturation, lightness);
+        let hsl = Hsl::new(hue * 360.0, saturation, lightness);
         let c_srgb = palette::Srgb::from_color(hsl);
+        let oklch = Oklch::from_color(c_srgb);
         (
-            ColorGenerator { hsl },
+            ColorGenerator {
+                hsl,
+                oklch,
+                start_hue: hue * 360.0,
+                start_oklch_hue: oklch.hue.to_positive_degrees(),
+                options: ColorOptions::DEFAULT,
+                palette: None,
+                palette_by_lightness: Vec::new(),
+                palette_index: 0,
+                adjusted: 0,
+            },
             Color::rgba(c_srgb.red, c_srgb.green, c_srgb.blue, 1.0),
         )
     }
 
-    pub fn rand_color(&mut self, rng: &mut rand::prelude::ThreadRng) -> Color {
-        //let clamped_hue: f32 = num::clamp(circle_hsl.hue.to_degrees() + rng.gen_range(-30.0..30.0), 0.0, 360.0);
-        let clamped_hue: f32 = rng.gen_range(0.0..30.0);
-        self.hsl.hue = palette::RgbHue::from_degrees(clamped_hue);
+    // with a palette, the first color is the palette entry closest to the start color
+    pub fn from_start_color(
+        start_color: &StartColor,
+        options: ColorOptions,
+        palette: Option<&ColorPalette>,
+    ) -> (ColorGenerator, Color) {
+        let (mut generator, mut color) = ColorGenerator::new(
+            start_color.hue,
+            start_color.saturation,
+            start_color.lightness,
+        );
+        generator.options = options;
+        if let Some(palette) = palette.filter(|p| !p.entries.is_empty()) {
+            let lightness = |c: Color| {
+                let [r, g, b, _] = c.as_rgba_f32();
+                0.2126 * r + 0.7152 * g + 0.0722 * b
+            };
+            let distance = |c: Color| {
+                let (a, b) = (c.as_rgba_f32(), color.as_rgba_f32());
+                (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)
+            };
+            let mut by_lightness: Vec<usize> = (0..palette.entries.len()).collect();
+            by_lightness.sort_by(|a, b| {
+                lightness(palette.entries[*a].color)
+                    .total_cmp(&lightness(palette.entries[*b].color))
+            });
+            generator.palette_index = (0..palette.entries.len())
+                .min_by(|a, b| {
+                    distance(palette.entries[*a].color)
+                        .total_cmp(&distance(palette.entries[*b].color))
+                })
+                .unwrap_or(0);
+            color = palette.entries[generator.palette_index].color;
+            generator.palette = Some(palette.clone());
+            generator.palette_by_lightness = by_lightness;
+        }
+        (generator, color)
+    }
+
+    pub fn rand_color(&mut self, rng: &mut impl Rng) -> Color {
+        if let Some(palette) = &self.palette {
+            self.palette_index = palette.pick(self.options.palette_mode, rng);
+            return palette.entries[self.palette_index].color;
+        }
+        let offsets = self.options.strategy.hue_offsets();
+        let spread = self.options.hue_spread.abs();
+        let mut hue = offsets[rng.gen_range(0..offsets.len())];
+        if spread > 0.0 {
+            hue += rng.gen_range(-spread..=spread);
+        }
+        if self.options.space == ColorSpace::Oklch {
+            self.oklch.hue = OklabHue::from_degrees(self.start_oklch_hue + hue);
+            return oklch_to_color(self.oklch, 1.0);
+        }
+        hue += self.start_hue;
+        self.hsl.hue = palette::RgbHue::from_degrees(hue);
         let c_srgb = palette::Srgb::from_color(self.hsl);
         Color::rgba(c_srgb.red, c_srgb.green, c_srgb.blue, 1.0)
     }
 
-    pub fn rand_color_variation(&mut self, rng: &mut rand::prelude::ThreadRng) -> Color {
+    pub fn rand_color_variation(&mut self, rng: &mut impl Rng) -> Color {
+        if let Some(palette) = &self.palette {
+            // step to a neighbouring entry at most, so colors stay on the palette
+            let last = palette.entries.len() - 1;
+            self.palette_index = match rng.gen_range(0..3) {
+                0 => self.palette_index.saturating_sub(1),
+                1 => self.palette_index,
+                _ => (self.palette_index + 1).min(last),
+            };
+            return palette.entries[self.palette_index].color;
+        }
+        if self.options.space == ColorSpace::Oklch {
+            // about the same perceived step as the HSL walk gives in its mid-range hues
+            self.oklch.chroma = num::clamp(
+                self.oklch.chroma + rng.gen_range(-0.003..0.003),
+                0.0,
+                OKLCH_MAX_CHROMA,
+            );
+            self.oklch.l = num::clamp(
+                self.oklch.l + rng.gen_range(-0.03..0.03),
+                OKLCH_LIGHTNESS.0,
+                OKLCH_LIGHTNESS.1,
+            );
+            return oklch_to_color(self.oklch, 1.0);
+        }
         self.hsl.saturation =
             num::clamp(self.hsl.saturation + rng.gen_range(-0.01..0.01), 0.0, 1.0);
         self.hsl.lightness = num::clamp(self.hsl.lightness + rng.gen_range(-0.05..0.05), 0.3, 0.9);
         let c_srgb = palette::Srgb::from_color(self.hsl);
         Color::rgba(c_srgb.red, c_srgb.green, c_srgb.blue, 1.0)
     }
+
+    // makes color meet the min_contrast option against each of others by moving its lightness
+    // as little as possible, keeping hue and chroma; if no lightness works, takes the one
+    // with the most contrast against the worst of them. With a palette, takes the nearest entry
+    // that meets it instead, or the entry with the most contrast, so colors stay on the palette
+    pub fn constrain(&mut self, color: Color, others: &[Color]) -> Color {
+        let Some(min) = self.options.min_contrast else {
+            return color;
+        };
+        let worst = |c: Color| {
+            others
+                .iter()
+                .map(|o| min.contrast(c, *o))
+                .fold(f32::INFINITY, f32::min)
+        };
+        if worst(color) >= min.minimum() {
+            return color;
+        }
+        self.adjusted += 1;
+        if let Some(palette) = &self.palette {
+            let lab = |c: Color| Oklab::from_color(Srgb::new(c.r(), c.g(), c.b()));
+            let target = lab(color);
+            let distance = |c: Color| {
+                let c = lab(c);
+                (c.l - target.l).powi(2) + (c.a - target.a).powi(2) + (c.b - target.b).powi(2)
+            };
+            let entries = palette.entries.iter().map(|e| e.color);
+            let nearest = entries
+                .clone()
+                .filter(|c| worst(*c) >= min.minimum())
+                .min_by(|a, b| distance(*a).total_cmp(&distance(*b)));
+            return nearest
+                .or_else(|| entries.max_by(|a, b| worst(*a).total_cmp(&worst(*b))))
+                .unwrap_or(color);
+        }
+        let oklch = Oklch::from_color(Srgb::new(color.r(), color.g(), color.b()));
+        let mut best = (color, worst(color));
+        for step in 1..=50 {
+            for direction in [1.0, -1.0] {
+                let l = oklch.l + direction * step as f32 * 0.02;
+                if !(0.0..=1.0).contains(&l) {
+                    continue;
+                }
+                let candidate = oklch_to_color(Oklch { l, ..oklch }, color.a());
+                let contrast = worst(candidate);
+                if contrast >= min.minimum() {
+                    return candidate;
+                }
+                if contrast > best.1 {
+                    best = (candidate, contrast);
+                }
+            }
+        }
+        best.0
+    }
+
+    pub fn adjusted_count(&self) -> usize {
+        self.adjusted
+    }
+
+    // maps t in 0.0..=1.0 onto the lightness range the variations stay within, keeping hue and
+    // saturation; with a palette, onto its entries from darkest to lightest
+    pub fn shade(&self, t: f32) -> Color {
+        if let Some(palette) = &self.palette {
+            let last = self.palette_by_lightness.len() - 1;
+            let i = (num::clamp(t, 0.0, 1.0) * last as f32).round() as usize;
+            return palette.entries[self.palette_by_lightness[i]].color;
+        }
+        if self.options.space == ColorSpace::Oklch {
+            let mut oklch = self.oklch;
+            oklch.l = OKLCH_LIGHTNESS.0
+                + (OKLCH_LIGHTNESS.1 - OKLCH_LIGHTNESS.0) * num::clamp(t, 0.0, 1.0);
+            return oklch_to_color(oklch, 1.0);
+        }
+        let mut hsl = self.hsl;
+        hsl.lightness = 0.3 + 0.6 * num::clamp(t, 0.0, 1.0);
+        let c_srgb = palette::Srgb::from_color(hsl);
+        Color::rgba(c_srgb.red, c_srgb.green, c_srgb.blue, 1.0)
+    }
+}
+
+// converts to sRGB, keeping lightness and hue and reducing chroma until the color is in gamut
+pub fn oklch_to_color(oklch: Oklch, alpha: f32) -> Color {
+    let in_gamut = |chroma: f32| Srgb::from_color_unclamped(Oklch { chroma, ..oklch });
+    let mut c_srgb = in_gamut(oklch.chroma);
+    if !c_srgb.is_within_bounds() {
+        let (mut lo, mut hi) = (0.0, oklch.chroma);
+        for _ in 0..16 {
+            let mid = f32::midpoint(lo, hi);
+            if in_gamut(mid).is_within_bounds() {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        // what's left of the error is rounding
+        c_srgb = in_gamut(lo).clamp();
+    }
+    Color::rgba(c_srgb.red, c_srgb.green, c_srgb.blue, alpha)
+}
+
+// hue in degrees, saturation and lightness of color, on the scales animate_color offsets them by
+pub fn color_components(color: Color, space: ColorSpace) -> [f32; 3] {
+    let c_srgb = Srgb::new(color.r(), color.g(), color.b());
+    match space {
+        ColorSpace::Hsl => {
+            let hsl = Hsl::from_color(c_srgb);
+            [hsl.hue.to_positive_degrees(), hsl.saturation, hsl.lightness]
+        }
+        ColorSpace::Oklch => {
+            let oklch = Oklch::from_color(c_srgb);
+            [oklch.hue.to_positive_degrees(), oklch.chroma * 4.0, oklch.l]
+        }
+    }
+}
+
+// color shifted by a circle's summed modulator offsets; in Oklch, saturation moves chroma at a
+// quarter the scale
+pub fn animate_color(color: Color, m: &Modulation, space: ColorSpace) -> Color {
+    let c_srgb = Srgb::new(color.r(), color.g(), color.b());
+    let alpha = num::clamp(color.a() + m.alpha, 0.0, 1.0);
+    match space {
+        ColorSpace::Hsl => {
+            let mut hsl = Hsl::from_color(c_srgb);
+            hsl.hue += m.hue;
+            hsl.saturation = num::clamp(hsl.saturation + m.saturation, 0.0, 1.0);
+            hsl.lightness = num::clamp(hsl.lightness + m.lightness, 0.3, 0.9);
+            let c_srgb = Srgb::from_color(hsl);
+            Color::rgba(c_srgb.red, c_srgb.green, c_srgb.blue, alpha)
+        }
+        ColorSpace::Oklch => {
+            let mut oklch = Oklch::from_color(c_srgb);
+            oklch.hue += m.hue;
+            oklch.chroma = num::clamp(oklch.chroma + m.saturation / 4.0, 0.0, OKLCH_MAX_CHROMA);
+            oklch.l = num::clamp(oklch.l + m.lightness, OKLCH_LIGHTNESS.0, OKLCH_LIGHTNESS.1);
+            oklch_to_color(oklch, alpha)
+        }
+    }
 }
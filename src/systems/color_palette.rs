@@ -0,0 +1,311 @@
+use bevy::asset::{
+    AssetLoader, AssetServer, Assets, BoxedFuture, Handle, LoadContext, LoadState, LoadedAsset,
+};
+use bevy::ecs::{
+    component::Component,
+    entity::Entity,
+    system::{Commands, Query, Res},
+};
+use bevy::log::error;
+use bevy::reflect::TypeUuid;
+use bevy::render::color::Color;
+
+#[derive(Clone, Debug)]
+pub struct PaletteEntry {
+    pub color: Color,
+    // relative likelihood of the entry being picked in weighted mode
+    pub weight: f32,
+    pub name: Option<String>,
+}
+
+// a fixed set of colors, loaded from GIMP .gpl, hex list (.hex) or Paint.NET .txt files
+#[derive(Clone, Debug, Default, TypeUuid)]
+#[uuid = "6f4b3a52-9a1e-4c35-bd0e-58a7f1c2e913"]
+pub struct ColorPalette {
+    pub name: Option<String>,
+    pub entries: Vec<PaletteEntry>,
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaletteMode {
+    // every entry is equally likely
+    #[default]
+    Uniform,
+    // entries are picked in proportion to their weights
+    Weighted,
+}
+
+#[derive(Debug)]
+pub struct PaletteParseError {
+    // 1-based
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for PaletteParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for PaletteParseError {}
+
+fn parse_error(line: usize, message: impl Into<String>) -> PaletteParseError {
+    PaletteParseError {
+        line: line + 1,
+        message: message.into(),
+    }
+}
+
+fn parse_weight(line: usize, word: Option<&str>) -> Result<f32, PaletteParseError> {
+    match word {
+        None => Ok(1.0),
+        Some(w) => match w.parse::<f32>() {
+            Ok(weight) if weight >= 0.0 => Ok(weight),
+            _ => Err(parse_error(line, format!("bad weight \"{w}\""))),
+        },
+    }
+}
+
+impl ColorPalette {
+    // GIMP palette: a "GIMP Palette" header, optional Name:/Columns: lines, '#' comments,
+    // then "R G B [name]" lines with 0..=255 components
+    pub fn parse_gpl(text: &str) -> Result<ColorPalette, PaletteParseError> {
+        let mut lines = text.lines().enumerate();
+        match lines.next() {
+            Some((_, header)) if header.trim() == "GIMP Palette" => {}
+            _ => return Err(parse_error(0, "missing \"GIMP Palette\" header")),
+        }
+        let mut palette = ColorPalette::default();
+        for (n, line) in lines {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("Columns:") {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix("Name:") {
+                palette.name = Some(name.trim().to_string());
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let mut rgb = [0u8; 3];
+            for c in &mut rgb {
+                let word = words
+                    .next()
+                    .ok_or_else(|| parse_error(n, "expected three color components"))?;
+                *c = word
+                    .parse()
+                    .map_err(|_| parse_error(n, format!("bad color component \"{word}\"")))?;
+            }
+            let name: Vec<&str> = words.collect();
+            palette.entries.push(PaletteEntry {
+                color: Color::rgb_u8(rgb[0], rgb[1], rgb[2]),
+                weight: 1.0,
+                name: (!name.is_empty()).then(|| name.join(" ")),
+            });
+        }
+        Ok(palette)
+    }
+
+    // hex list as exported by Lospec: one "rrggbb" or "#rrggbb" per line, optionally followed by a weight
+    pub fn parse_hex(text: &str) -> Result<ColorPalette, PaletteParseError> {
+        let mut palette = ColorPalette::default();
+        for (n, line) in text.lines().enumerate() {
+            let mut words = line.split_whitespace();
+            let hex = match words.next() {
+                Some(hex) if !hex.starts_with("//") && !hex.starts_with(';') => hex,
+                _ => continue,
+            };
+            let color = Color::hex(hex.trim_start_matches('#'))
+                .map_err(|_| parse_error(n, format!("bad hex color \"{hex}\"")))?;
+            palette.entries.push(PaletteEntry {
+                color,
+                weight: parse_weight(n, words.next())?,
+                name: None,
+            });
+        }
+        Ok(palette)
+    }
+
+    // Paint.NET palette: ';' comments and one "AARRGGBB" per line
+    pub fn parse_paint_net(text: &str) -> Result<ColorPalette, PaletteParseError> {
+        let mut palette = ColorPalette::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            if line.len() != 8 || !line.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(parse_error(n, format!("expected AARRGGBB, got \"{line}\"")));
+            }
+            let (alpha, rgb) = line.split_at(2);
+            let color = Color::hex(format!("{rgb}{alpha}"))
+                .map_err(|_| parse_error(n, format!("bad color \"{line}\"")))?;
+            palette.entries.push(PaletteEntry {
+                color,
+                weight: 1.0,
+                name: None,
+            });
+        }
+        Ok(palette)
+    }
+
+    // picks which parser to use from the file extension
+    pub fn parse(extension: &str, text: &str) -> Result<ColorPalette, PaletteParseError> {
+        let palette = match extension {
+            "gpl" => ColorPalette::parse_gpl(text)?,
+            "txt" => ColorPalette::parse_paint_net(text)?,
+            _ => ColorPalette::parse_hex(text)?,
+        };
+        if palette.entries.is_empty() {
+            return Err(parse_error(0, "palette has no colors"));
+        }
+        Ok(palette)
+    }
+
+    // index of a random entry, according to the mode
+    pub fn pick(&self, mode: PaletteMode, rng: &mut impl rand::Rng) -> usize {
+        let total: f32 = self.entries.iter().map(|e| e.weight).sum();
+        if mode == PaletteMode::Uniform || total <= 0.0 {
+            return rng.gen_range(0..self.entries.len());
+        }
+        let mut target = rng.gen_range(0.0..total);
+        for (i, entry) in self.entries.iter().enumerate() {
+            if target < entry.weight {
+                return i;
+            }
+            target -= entry.weight;
+        }
+        self.entries.len() - 1
+    }
+}
+
+#[derive(Default)]
+pub struct ColorPaletteLoader;
+
+impl AssetLoader for ColorPaletteLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let extension = load_context
+                .path()
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            let palette = ColorPalette::parse(&extension, std::str::from_utf8(bytes)?)?;
+            load_context.set_default_asset(LoadedAsset::new(palette));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["gpl", "hex", "txt"]
+    }
+}
+
+// put on a generator entity until its palette has loaded; generators don't set up while it's there
+#[derive(Component)]
+pub struct PendingPalette(pub Handle<ColorPalette>);
+
+// the loaded palette of a generator entity
+#[derive(Component)]
+pub struct TexturePalette(pub ColorPalette);
+
+pub fn resolve_pending_palettes(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    palettes: Res<Assets<ColorPalette>>,
+    query: Query<(Entity, &PendingPalette)>,
+) {
+    for (entity, pending) in &query {
+        if let Some(palette) = palettes.get(&pending.0) {
+            commands
+                .entity(entity)
+                .remove::<PendingPalette>()
+                .insert(TexturePalette(palette.clone()));
+        } else if asset_server.get_load_state(&pending.0) == LoadState::Failed {
+            error!("couldn't load palette; generating without it");
+            commands.entity(entity).remove::<PendingPalette>();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_gimp_palettes() {
+        let palette = ColorPalette::parse(
+            "gpl",
+            "GIMP Palette\nName: Sunset\nColumns: 4\n# warm\n255 0 0 Bright Red\n  0 128 255\n",
+        )
+        .unwrap();
+        assert_eq!(palette.name.as_deref(), Some("Sunset"));
+        assert_eq!(palette.entries.len(), 2);
+        assert_eq!(palette.entries[0].color, Color::rgb_u8(255, 0, 0));
+        assert_eq!(palette.entries[0].name.as_deref(), Some("Bright Red"));
+        assert_eq!(palette.entries[1].color, Color::rgb_u8(0, 128, 255));
+        assert_eq!(palette.entries[1].name, None);
+    }
+
+    #[test]
+    fn gimp_errors_give_the_line() {
+        let err = ColorPalette::parse_gpl("Palette\n255 0 0").unwrap_err();
+        assert_eq!(err.line, 1);
+        let err = ColorPalette::parse_gpl("GIMP Palette\n255 0 0\n255 0 300\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 3: bad color component \"300\"");
+        let err = ColorPalette::parse_gpl("GIMP Palette\n255 0\n").unwrap_err();
+        assert_eq!(err.line, 2);
+    }
+
+    #[test]
+    fn parses_hex_lists_with_weights() {
+        let palette =
+            ColorPalette::parse("hex", "; lospec\nff0000\n#00ff00 3\n\n// done\n").unwrap();
+        let colors: Vec<Color> = palette.entries.iter().map(|e| e.color).collect();
+        assert_eq!(
+            colors,
+            vec![Color::rgb(1.0, 0.0, 0.0), Color::rgb(0.0, 1.0, 0.0)]
+        );
+        assert!((palette.entries[1].weight - 3.0).abs() < f32::EPSILON);
+        assert_eq!(
+            ColorPalette::parse_hex("ff0000\nzz0000").unwrap_err().line,
+            2
+        );
+        assert_eq!(
+            ColorPalette::parse_hex("ff0000 -1")
+                .unwrap_err()
+                .to_string(),
+            "line 1: bad weight \"-1\""
+        );
+    }
+
+    #[test]
+    fn parses_paint_net_palettes() {
+        let palette =
+            ColorPalette::parse("txt", ";paint.net Palette File\nFFFF0000\n800000FF\n").unwrap();
+        assert_eq!(palette.entries[0].color, Color::rgb(1.0, 0.0, 0.0));
+        assert_eq!(palette.entries[1].color, Color::rgba_u8(0, 0, 255, 128));
+        assert_eq!(ColorPalette::parse_paint_net("FF0000").unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn an_empty_palette_is_an_error() {
+        let err = ColorPalette::parse("hex", "; nothing here\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: palette has no colors");
+    }
+
+    #[test]
+    fn weighted_picks_follow_the_weights() {
+        let mut palette = ColorPalette::parse_hex("000000 0\nffffff 1\n").unwrap();
+        let mut rng = rand::thread_rng();
+        assert!((0..100).all(|_| palette.pick(PaletteMode::Weighted, &mut rng) == 1));
+        palette.entries[1].weight = 0.0;
+        // with no weight anywhere, any entry will do
+        assert!((0..100).all(|_| palette.pick(PaletteMode::Weighted, &mut rng) < 2));
+    }
+}
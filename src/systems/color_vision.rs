@@ -0,0 +1,429 @@
+use bevy::app::{App, CoreStage, Plugin};
+use bevy::asset::{AssetServer, Assets, Handle, HandleId, LoadState};
+use bevy::core_pipeline::{
+    clear_color::ClearColor, clear_color::ClearColorConfig, core_2d::Camera2d,
+};
+use bevy::ecs::{
+    entity::Entity,
+    query::With,
+    system::{Local, Query, Res, ResMut},
+};
+use bevy::input::{keyboard::KeyCode, Input};
+use bevy::log::{info, warn};
+use bevy::render::{color::Color, texture::Image};
+use bevy::sprite::ColorMaterial;
+use bevy::utils::HashMap;
+use palette::{FromColor, LinSrgb, Oklab, Srgb};
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::systems::canvas::CanvasSprite;
+use crate::systems::color_generator::{ColorGenerator, ColorMapping};
+use crate::systems::color_palette::ColorPalette;
+use crate::systems::dynamic_textures::{DynamicTextures, RenderToTextureDescriptor};
+
+// Oklab distance below which two colors are hard to tell apart at a glance
+pub const MIN_DISTINGUISHABLE_DELTA_E: f32 = 0.08;
+// how many colors color_set samples from a generator without a palette
+const COLOR_SET_SIZE: usize = 32;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorVision {
+    #[default]
+    Normal,
+    // no red cones
+    Protanopia,
+    // no green cones
+    Deuteranopia,
+    // no blue cones
+    Tritanopia,
+}
+
+impl ColorVision {
+    pub const DEFICIENCIES: [ColorVision; 3] = [
+        ColorVision::Protanopia,
+        ColorVision::Deuteranopia,
+        ColorVision::Tritanopia,
+    ];
+
+    // Machado, Oliveira and Fernandes (2009) at full severity, on linear RGB
+    fn matrix(self) -> Option<[[f32; 3]; 3]> {
+        match self {
+            ColorVision::Normal => None,
+            ColorVision::Protanopia => Some([
+                [0.152_286, 1.052_583, -0.204_868],
+                [0.114_503, 0.786_281, 0.099_216],
+                [-0.003_882, -0.048_116, 1.051_998],
+            ]),
+            ColorVision::Deuteranopia => Some([
+                [0.367_322, 0.860_646, -0.227_968],
+                [0.280_085, 0.672_501, 0.047_413],
+                [-0.011_820, 0.042_940, 0.968_881],
+            ]),
+            ColorVision::Tritanopia => Some([
+                [1.255_528, -0.076_749, -0.178_779],
+                [-0.078_411, 0.930_809, 0.147_602],
+                [0.004_733, 0.691_367, 0.303_900],
+            ]),
+        }
+    }
+
+    fn next(self) -> ColorVision {
+        match self {
+            ColorVision::Normal => ColorVision::Protanopia,
+            ColorVision::Protanopia => ColorVision::Deuteranopia,
+            ColorVision::Deuteranopia => ColorVision::Tritanopia,
+            ColorVision::Tritanopia => ColorVision::Normal,
+        }
+    }
+
+    // how an sRGB color looks with this vision, keeping alpha
+    pub fn simulate(self, c: [f32; 4]) -> [f32; 4] {
+        let Some(m) = self.matrix() else {
+            return c;
+        };
+        let lin = Srgb::new(c[0], c[1], c[2]).into_linear();
+        let rgb = [lin.red, lin.green, lin.blue];
+        let row = |r: [f32; 3]| (r[0] * rgb[0] + r[1] * rgb[1] + r[2] * rgb[2]).clamp(0.0, 1.0);
+        let out = Srgb::from_linear(LinSrgb::new(row(m[0]), row(m[1]), row(m[2])));
+        [out.red, out.green, out.blue, c[3]]
+    }
+
+    pub fn simulate_color(self, color: Color) -> Color {
+        let c = self.simulate(color.as_rgba_f32());
+        Color::rgba(c[0], c[1], c[2], c[3])
+    }
+}
+
+fn delta_e(a: Color, b: Color) -> f32 {
+    let (a, b) = (
+        Oklab::from_color(Srgb::new(a.r(), a.g(), a.b())),
+        Oklab::from_color(Srgb::new(b.r(), b.g(), b.b())),
+    );
+    ((a.l - b.l).powi(2) + (a.a - b.a).powi(2) + (a.b - b.b).powi(2)).sqrt()
+}
+
+// a sample of the colors a descriptor's generator draws, the same every time: all of its
+// palette's entries, the shades its color mapping spreads over, or the hues its strategy picks
+// with some of their variations
+pub fn color_set(desc: &RenderToTextureDescriptor, palette: Option<&ColorPalette>) -> Vec<Color> {
+    if let Some(palette) = palette.filter(|p| !p.entries.is_empty()) {
+        return palette.entries.iter().map(|e| e.color).collect();
+    }
+    let (mut generator, first) =
+        ColorGenerator::from_start_color(&desc.start_color, desc.color, None);
+    if desc.color.mapping != ColorMapping::Sequence {
+        let last = (COLOR_SET_SIZE - 1) as f32;
+        return (0..COLOR_SET_SIZE)
+            .map(|i| generator.shade(i as f32 / last))
+            .collect();
+    }
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut colors = vec![first];
+    while colors.len() < COLOR_SET_SIZE {
+        colors.push(if colors.len() % 4 == 0 {
+            generator.rand_color(&mut rng)
+        } else {
+            generator.rand_color_variation(&mut rng)
+        });
+    }
+    colors
+}
+
+// how far apart two color sets look: the mean distance from each color to the nearest one in the
+// other set, both ways round
+fn set_distance(a: &[Color], b: &[Color]) -> f32 {
+    let directed = |from: &[Color], to: &[Color]| {
+        from.iter()
+            .map(|x| {
+                to.iter()
+                    .map(|y| delta_e(*x, *y))
+                    .fold(f32::INFINITY, f32::min)
+            })
+            .sum::<f32>()
+            / from.len().max(1) as f32
+    };
+    0.5 * (directed(a, b) + directed(b, a))
+}
+
+// two textures whose colors look alike with a color vision deficiency, though not without
+#[derive(Clone, Debug)]
+pub struct VisionConflict {
+    pub a: &'static str,
+    pub b: &'static str,
+    pub vision: ColorVision,
+    pub delta_e: f32,
+}
+
+impl std::fmt::Display for VisionConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} and {} are hard to tell apart with {:?} (delta E {:.3})",
+            self.a, self.b, self.vision, self.delta_e
+        )
+    }
+}
+
+// the deficiencies that make two textures' color sets, from color_set, indistinguishable
+pub fn pair_conflicts(
+    (a, a_colors): (&'static str, &[Color]),
+    (b, b_colors): (&'static str, &[Color]),
+) -> Vec<VisionConflict> {
+    if set_distance(a_colors, b_colors) < MIN_DISTINGUISHABLE_DELTA_E {
+        // alike for everyone, so nothing a deficiency changes
+        return Vec::new();
+    }
+    ColorVision::DEFICIENCIES
+        .iter()
+        .filter_map(|vision| {
+            let simulate = |colors: &[Color]| -> Vec<Color> {
+                colors.iter().map(|c| vision.simulate_color(*c)).collect()
+            };
+            let d = set_distance(&simulate(a_colors), &simulate(b_colors));
+            (d < MIN_DISTINGUISHABLE_DELTA_E).then_some(VisionConflict {
+                a,
+                b,
+                vision: *vision,
+                delta_e: d,
+            })
+        })
+        .collect()
+}
+
+// every pair of descriptors that a color vision deficiency makes indistinguishable; palette gives
+// the loaded palette of a path descriptors draw from
+pub fn validate_descriptors<'p>(
+    descs: &[RenderToTextureDescriptor],
+    palette: impl Fn(&str) -> Option<&'p ColorPalette>,
+) -> Vec<VisionConflict> {
+    let sets: Vec<Vec<Color>> = descs
+        .iter()
+        .map(|desc| color_set(desc, desc.color.palette.and_then(&palette)))
+        .collect();
+    let mut conflicts = Vec::new();
+    for (i, a) in descs.iter().enumerate() {
+        for (j, b) in descs.iter().enumerate().skip(i + 1) {
+            conflicts.extend(pair_conflicts((a.name, &sets[i]), (b.name, &sets[j])));
+        }
+    }
+    conflicts
+}
+
+// warns about each added texture that a deficiency makes hard to tell from one added before it,
+// once the palette it draws from, if any, has loaded or failed to
+pub fn warn_vision_conflicts(
+    dyntex: Res<DynamicTextures>,
+    asset_server: Res<AssetServer>,
+    palettes: Res<Assets<ColorPalette>>,
+    mut checked: Local<Vec<(&'static str, Vec<Color>)>>,
+) {
+    for desc in dyntex.descriptors() {
+        if checked.iter().any(|(name, _)| *name == desc.name) {
+            continue;
+        }
+        let palette = match desc.color.palette {
+            Some(path) => {
+                let handle: Handle<ColorPalette> = asset_server.get_handle(path);
+                match asset_server.get_load_state(&handle) {
+                    LoadState::Loaded => palettes.get(&handle),
+                    LoadState::Failed => None,
+                    _ => continue,
+                }
+            }
+            None => None,
+        };
+        let colors = color_set(desc, palette);
+        for (other, other_colors) in checked.iter() {
+            for conflict in pair_conflicts((other, other_colors), (desc.name, &colors)) {
+                warn!("{}", conflict);
+            }
+        }
+        checked.push((desc.name, colors));
+    }
+}
+
+// the simulation the debug view shows; F8 cycles through them
+#[derive(Default)]
+pub struct ColorVisionDebug {
+    pub vision: ColorVision,
+}
+
+// what simulate_color_vision overwrote, put back before the generators run so they never see
+// simulated colors
+#[derive(Default)]
+struct OriginalColors {
+    materials: HashMap<HandleId, Color>,
+    images: HashMap<HandleId, Vec<u8>>,
+    cameras: HashMap<Entity, Color>,
+    clear_color: Option<Color>,
+}
+
+pub struct ColorVisionPlugin;
+
+impl Plugin for ColorVisionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ColorVisionDebug>()
+            .init_resource::<OriginalColors>()
+            .add_system(cycle_color_vision)
+            .add_system_to_stage(CoreStage::PreUpdate, restore_original_colors)
+            .add_system_to_stage(CoreStage::PostUpdate, simulate_color_vision);
+    }
+}
+
+fn cycle_color_vision(keys: Res<Input<KeyCode>>, mut view: ResMut<ColorVisionDebug>) {
+    if keys.just_pressed(KeyCode::F8) {
+        view.vision = view.vision.next();
+        info!("simulating {:?} color vision", view.vision);
+    }
+}
+
+fn restore_original_colors(
+    mut originals: ResMut<OriginalColors>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut images: ResMut<Assets<Image>>,
+    mut cameras: Query<&mut Camera2d>,
+    mut clear_color: ResMut<ClearColor>,
+) {
+    for (id, color) in originals.materials.drain() {
+        if let Some(material) = materials.get_mut(&Handle::weak(id)) {
+            material.color = color;
+        }
+    }
+    for (id, data) in originals.images.drain() {
+        if let Some(image) = images.get_mut(&Handle::weak(id)) {
+            image.data = data;
+        }
+    }
+    for (entity, color) in originals.cameras.drain() {
+        if let Ok(mut camera) = cameras.get_mut(entity) {
+            camera.clear_color = ClearColorConfig::Custom(color);
+        }
+    }
+    if let Some(color) = originals.clear_color.take() {
+        clear_color.0 = color;
+    }
+}
+
+// recolors every dynamic texture's inputs, and the main view's clear color, as the debug vision sees them
+fn simulate_color_vision(
+    view: Res<ColorVisionDebug>,
+    mut originals: ResMut<OriginalColors>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut images: ResMut<Assets<Image>>,
+    canvas_sprites: Query<&Handle<Image>, With<CanvasSprite>>,
+    mut cameras: Query<(Entity, &mut Camera2d)>,
+    mut clear_color: ResMut<ClearColor>,
+) {
+    let vision = view.vision;
+    if vision == ColorVision::Normal {
+        return;
+    }
+    for (id, material) in materials.iter_mut() {
+        originals.materials.insert(id, material.color);
+        material.color = vision.simulate_color(material.color);
+    }
+    for handle in &canvas_sprites {
+        if let Some(image) = images.get_mut(handle) {
+            originals.images.insert(handle.id, image.data.clone());
+            for p in image.data.chunks_exact_mut(4) {
+                let c = [0, 1, 2, 3].map(|ch| f32::from(p[ch]) / 255.0);
+                for (out, ch) in p.iter_mut().zip(vision.simulate(c)) {
+                    *out = (ch * 255.0).round() as u8;
+                }
+            }
+        }
+    }
+    for (entity, mut camera) in &mut cameras {
+        if let ClearColorConfig::Custom(color) = camera.clear_color {
+            originals.cameras.insert(entity, color);
+            camera.clear_color = ClearColorConfig::Custom(vision.simulate_color(color));
+        }
+    }
+    originals.clear_color = Some(clear_color.0);
+    clear_color.0 = vision.simulate_color(clear_color.0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::color_generator::ColorOptions;
+    use crate::systems::color_palette::PaletteEntry;
+    use crate::systems::dynamic_textures::{GeneratorParams, StartColor};
+
+    const RED: Color = Color::rgb(0.7, 0.2, 0.2);
+    const OLIVE: Color = Color::rgb(0.45, 0.45, 0.2);
+
+    const DESC: RenderToTextureDescriptor = RenderToTextureDescriptor {
+        name: "test",
+        functype: "Circles2",
+        size: 64,
+        start_color: StartColor {
+            hue: 0.6,
+            saturation: 0.7,
+            lightness: 0.5,
+        },
+        background_color: Color::BLACK,
+        color: ColorOptions::DEFAULT,
+        params: GeneratorParams::Default,
+        layers: &[],
+        inputs: &[],
+        filters: &[],
+        animation: &[],
+        formulas: None,
+        reveal: None,
+    };
+
+    #[test]
+    fn a_palette_is_its_own_color_set() {
+        let palette = ColorPalette {
+            name: None,
+            entries: [RED, OLIVE]
+                .iter()
+                .map(|color| PaletteEntry {
+                    color: *color,
+                    weight: 1.0,
+                    name: None,
+                })
+                .collect(),
+        };
+        assert_eq!(color_set(&DESC, Some(&palette)), vec![RED, OLIVE]);
+        // an empty palette falls back on the generator
+        assert_eq!(
+            color_set(&DESC, Some(&ColorPalette::default())).len(),
+            COLOR_SET_SIZE
+        );
+    }
+
+    #[test]
+    fn color_sets_are_the_same_every_time() {
+        let set = color_set(&DESC, None);
+        assert_eq!(set.len(), COLOR_SET_SIZE);
+        assert_eq!(set, color_set(&DESC, None));
+    }
+
+    #[test]
+    fn set_distance_is_symmetric_and_zero_for_the_same_set() {
+        let (a, b) = ([RED, Color::WHITE], [OLIVE]);
+        assert!(set_distance(&a, &a).abs() < f32::EPSILON);
+        assert!((set_distance(&a, &b) - set_distance(&b, &a)).abs() < 1e-6);
+        assert!(set_distance(&a, &b) > 0.0);
+    }
+
+    #[test]
+    fn finds_colors_only_a_deficiency_confuses() {
+        let conflicts = pair_conflicts(("red", &[RED]), ("olive", &[OLIVE]));
+        let visions: Vec<ColorVision> = conflicts.iter().map(|c| c.vision).collect();
+        assert_eq!(visions, vec![ColorVision::Deuteranopia]);
+        assert_eq!((conflicts[0].a, conflicts[0].b), ("red", "olive"));
+        // told apart by everyone
+        assert!(pair_conflicts(("red", &[RED]), ("white", &[Color::WHITE])).is_empty());
+        // confused by everyone, which is for the designer to see
+        assert!(pair_conflicts(("red", &[RED]), ("also red", &[RED])).is_empty());
+    }
+
+    #[test]
+    fn normal_vision_changes_nothing() {
+        let c = Color::rgba(0.3, 0.6, 0.9, 0.5);
+        assert_eq!(ColorVision::Normal.simulate_color(c), c);
+    }
+}
@@ -15,8 +15,26 @@ use bevy::{
 #[derive(Component, Default)]
 pub struct RenderToTexturePass;
 
-use super::circles::Circles1;
+use super::automaton::{Automaton, AutomatonParams};
 use super::circles::Circles2;
+use super::circles::{CircleMesh, Circles1, PaletteCycle, RestartReveal, RevealFinished};
+use super::layers::{LayeredTexture, TextureLayer};
+use super::patterns::{NoiseParams, StippleParams};
+use super::reaction_diffusion::{GrayScottParams, ReactionDiffusion};
+use super::texture_graph::{GraphNode, TextureGraph, TextureInput};
+use crate::systems::animation::{Modulator, Reveal};
+use crate::systems::audio::{AudioClip, AudioClipLoader, AudioInput};
+use crate::systems::canvas::Canvas;
+use crate::systems::clock::{tick_texture_clocks, TextureClock, TextureClockEvent};
+use crate::systems::color_generator::{ColorGenerator, ColorOptions};
+use crate::systems::color_palette::{ColorPalette, ColorPaletteLoader, PendingPalette};
+use crate::systems::color_vision::warn_vision_conflicts;
+use crate::systems::filters::{quantizes, Filter};
+use crate::systems::formula_sheet::{FormulaSheet, FormulaSheetLoader, TextureFormulas};
+use crate::systems::morph::MorphTextureEvent;
+use crate::systems::motion::{FlashAnalyzer, MotionSettings};
+use crate::systems::physics::{PhysicsParams, TextureImpulseEvent};
+use crate::systems::reactions::TextureReact;
 
 #[derive(Default)]
 pub struct AddDynamicTextureEvent {
@@ -28,44 +46,174 @@ pub struct DynamicTexturesPlugin;
 impl Plugin for DynamicTexturesPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<DynamicTextures>()
+            .init_resource::<CircleMesh>()
+            .init_resource::<MotionSettings>()
+            .init_resource::<FlashAnalyzer>()
+            .init_resource::<AudioInput>()
+            .add_asset::<ColorPalette>()
+            .init_asset_loader::<ColorPaletteLoader>()
+            .add_asset::<FormulaSheet>()
+            .init_asset_loader::<FormulaSheetLoader>()
+            .add_asset::<AudioClip>()
+            .init_asset_loader::<AudioClipLoader>()
             .add_event::<AddDynamicTextureEvent>()
+            .add_event::<TextureClockEvent>()
+            .add_event::<RevealFinished>()
+            .add_event::<RestartReveal>()
+            .add_event::<MorphTextureEvent>()
+            .add_event::<TextureImpulseEvent>()
+            .add_event::<TextureReact>()
+            // before the generators, so they all see this frame's time
+            .add_system_to_stage(CoreStage::PreUpdate, tick_texture_clocks)
+            .add_system(crate::systems::color_palette::resolve_pending_palettes)
+            .add_system(crate::systems::formula_sheet::apply_formula_sheets)
             .add_system(add_dynamic_texture_event_handler)
             .add_system(crate::systems::circles::circles1_add_circles_to_layer)
             .add_system(crate::systems::circles::circles2_add_circles_to_layer)
             .add_system(crate::systems::circles::circles1_update_colors)
-            .add_system(crate::systems::circles::circles2_update);
+            .add_system(crate::systems::physics::apply_texture_impulses)
+            // a reaction is drawn the frame it arrives
+            .add_system(
+                crate::systems::reactions::decay_reactions
+                    .before(crate::systems::reactions::react_to_events),
+            )
+            .add_system(
+                crate::systems::reactions::react_to_events
+                    .before(crate::systems::circles::circles2_update),
+            )
+            // before the circles read the levels
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                crate::systems::audio::resolve_pending_clip
+                    .before(crate::systems::audio::analyze_audio),
+            )
+            .add_system_to_stage(CoreStage::PreUpdate, crate::systems::audio::analyze_audio)
+            .add_system(
+                crate::systems::circles::restart_reveals
+                    .before(crate::systems::circles::circles2_update),
+            )
+            .add_system(crate::systems::circles::circles2_update)
+            // after the circles are drawn, so a morph's first frame and the layout it hands over
+            // are drawn the frame after, once the Morph component has been added or removed
+            .add_system(
+                crate::systems::morph::start_morphs.after(crate::systems::circles::circles2_update),
+            )
+            .add_system(
+                crate::systems::morph::update_morphs
+                    .after(crate::systems::circles::circles2_update),
+            )
+            .add_system(crate::systems::reaction_diffusion::reaction_diffusion_setup)
+            .add_system(crate::systems::reaction_diffusion::reaction_diffusion_update)
+            .add_system(crate::systems::automaton::automaton_setup)
+            .add_system(crate::systems::automaton::automaton_update)
+            .add_system(crate::systems::layers::layered_texture_setup)
+            .add_system(crate::systems::texture_graph::texture_graph_update)
+            .add_system(crate::systems::motion::measure_canvas_flashes)
+            .add_system(warn_vision_conflicts);
     }
 }
 
+// every functype a descriptor can have
+pub const FUNCTYPES: &[&str] = &[
+    "Circles1",
+    "Circles2",
+    "ReactionDiffusion",
+    "Automaton",
+    "Layered",
+    "Noise",
+    "Stipple",
+];
+
 fn add_dynamic_texture_event_handler(
     mut commands: Commands,
     mut images: ResMut<Assets<Image>>,
+    asset_server: Res<AssetServer>,
     mut events: EventReader<AddDynamicTextureEvent>,
     mut dyntex: ResMut<DynamicTextures>,
 ) {
     for e in events.iter() {
         if let Some(desc) = e.description {
-            if let Some(layer) = dyntex.get_available_render_layer() {
-                let handle_id = set_up_dynamic_texture(&mut commands, &mut images, &desc, layer);
-                dyntex.add_dynamic_texture(&desc, layer, Handle::weak(handle_id));
-                match desc.functype {
-                    "Circles1" => {
-                        commands.spawn().insert(Circles1::new(layer, &desc));
-                    }
-                    "Circles2" => {
-                        commands.spawn().insert(Circles2::new(layer, &desc));
-                    }
-                    _ => {
-                        unimplemented!("{}", desc.functype);
+            if !FUNCTYPES.contains(&desc.functype) {
+                error!(
+                    "not adding dynamic texture {}: unknown functype {}",
+                    desc.name, desc.functype
+                );
+                continue;
+            }
+            let Some(layer) = dyntex.get_available_render_layer() else {
+                error!(
+                    "not adding dynamic texture {}: out of render layers",
+                    desc.name
+                );
+                continue;
+            };
+            let inputs: Vec<&str> = desc.inputs.iter().map(|input| input.name).collect();
+            if let Err(err) = dyntex.graph.add_node(desc.name, &inputs) {
+                error!("not adding dynamic texture {}: {}", desc.name, err);
+                dyntex.release_render_layer(layer);
+                continue;
+            }
+            let handle_id = set_up_dynamic_texture(&mut commands, &mut images, &desc, layer);
+            dyntex.add_dynamic_texture(&desc, layer, Handle::weak(handle_id));
+            let mut generator = commands.spawn();
+            generator.insert(TextureClock::new(desc.name));
+            if let Some(path) = desc.color.palette {
+                // the generator waits for this to load before setting up
+                generator.insert(PendingPalette(asset_server.load(path)));
+            }
+            if !desc.inputs.is_empty() {
+                if animates(&desc) {
+                    warn!(
+                        "{}: a {} texture with inputs is drawn once as a graph node, without its animation",
+                        desc.name, desc.functype
+                    );
+                }
+                generator.insert(GraphNode::new(layer, &desc));
+                continue;
+            }
+            match desc.functype {
+                "Circles1" => {
+                    generator.insert(Circles1::new(layer, &desc));
+                }
+                "Circles2" => {
+                    generator.insert(Circles2::new(layer, &desc));
+                    if let Some(path) = desc.formulas {
+                        generator.insert(TextureFormulas::new(asset_server.load(path)));
                     }
                 }
-            } else {
-                // Ran out of render layers
+                "ReactionDiffusion" => {
+                    generator.insert(ReactionDiffusion::new(layer, &desc));
+                }
+                "Automaton" => {
+                    generator.insert(Automaton::new(layer, &desc));
+                }
+                "Layered" => {
+                    generator.insert(LayeredTexture::new(layer, &desc));
+                }
+                // drawn once on the CPU, the way a graph node without inputs is
+                "Noise" | "Stipple" => {
+                    generator.insert(GraphNode::new(layer, &desc));
+                }
+                // the rest were turned away above
+                _ => {}
             }
         }
     }
 }
 
+// whether a descriptor's generator changes the texture over time when it has no inputs
+fn animates(desc: &RenderToTextureDescriptor) -> bool {
+    match (desc.functype, desc.params) {
+        ("Circles1" | "Circles2", _) => true,
+        ("ReactionDiffusion", GeneratorParams::GrayScott(params)) => params.animate,
+        ("ReactionDiffusion", _) => GrayScottParams::default().animate,
+        ("Automaton", GeneratorParams::Automaton(params)) => params.animate,
+        ("Automaton", _) => AutomatonParams::default().animate,
+        _ => false,
+    }
+}
+
+// hue is a fraction of a full turn; saturation and lightness are 0.0..=1.0
 #[derive(Default, Copy, Clone, Debug)]
 pub struct StartColor {
     pub hue: f32,
@@ -73,12 +221,31 @@ pub struct StartColor {
     pub lightness: f32,
 }
 
+impl StartColor {
+    pub fn to_color(self) -> Color {
+        ColorGenerator::new(self.hue, self.saturation, self.lightness).1
+    }
+}
+
 impl std::fmt::Display for StartColor {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{}/{}/{}", self.hue, self.saturation, self.lightness)
     }
 }
 
+// generator-specific settings; generators fall back to their defaults when given ones for another functype
+#[derive(Default, Copy, Clone, Debug)]
+pub enum GeneratorParams {
+    #[default]
+    Default,
+    GrayScott(GrayScottParams),
+    Automaton(AutomatonParams),
+    Noise(NoiseParams),
+    Stipple(StippleParams),
+    PaletteCycle(PaletteCycle),
+    Physics(PhysicsParams),
+}
+
 #[derive(Component, Clone, Copy)]
 pub struct RenderToTextureDescriptor {
     pub name: &'static str,
@@ -86,6 +253,21 @@ pub struct RenderToTextureDescriptor {
     pub size: u32,
     pub start_color: StartColor,
     pub background_color: Color,
+    pub color: ColorOptions,
+    pub params: GeneratorParams,
+    // generators composited bottom to top over the background color, for the "Layered" functype
+    pub layers: &'static [TextureLayer],
+    // other dynamic textures this one is built from; they're generated first
+    pub inputs: &'static [TextureInput],
+    // post-processing applied in order once the texture is generated
+    pub filters: &'static [Filter],
+    // modulators that animate each circle, for "Circles2"
+    pub animation: &'static [Modulator],
+    // asset path of a .formulas sheet of expressions that set circle properties each frame, for
+    // "Circles2"
+    pub formulas: Option<&'static str>,
+    // grows the circles in one by one when the texture is first made, for "Circles2"
+    pub reveal: Option<Reveal>,
 }
 
 #[derive(Default)]
@@ -93,6 +275,10 @@ pub struct DynamicTextures {
     list: Vec<(u8, (Handle<Image>, RenderToTextureDescriptor))>,
     map: HashMap<String, (Handle<Image>, u8)>,
     highest_render_layer: u8,
+    graph: TextureGraph,
+    // latest CPU-side pixels of each texture that has them, with a version that goes up on every change
+    canvases: HashMap<String, (Canvas, u64)>,
+    canvas_version: u64,
 }
 
 impl DynamicTextures {
@@ -100,6 +286,58 @@ impl DynamicTextures {
         self.map.get(name)
     }
 
+    pub fn descriptors(&self) -> impl Iterator<Item = &RenderToTextureDescriptor> {
+        self.list.iter().map(|(_, (_, desc))| desc)
+    }
+
+    pub fn graph(&self) -> &TextureGraph {
+        &self.graph
+    }
+
+    pub fn canvas(&self, name: &str) -> Option<&Canvas> {
+        self.canvases.get(name).map(|(canvas, _)| canvas)
+    }
+
+    pub fn canvas_version(&self, name: &str) -> Option<u64> {
+        self.canvases.get(name).map(|(_, version)| *version)
+    }
+
+    // every published canvas with its name and version
+    pub fn canvases(&self) -> impl Iterator<Item = (&str, &Canvas, u64)> {
+        self.canvases
+            .iter()
+            .map(|(name, (canvas, version))| (name.as_str(), canvas, *version))
+    }
+
+    // which palette color each pixel is, for textures that end with a Quantize filter
+    pub fn palette_indices(&self, name: &str) -> Option<&[u8]> {
+        self.canvas(name)?.palette_indices.as_deref()
+    }
+
+    // whether each frame of a texture is wanted as a published canvas: by the textures that use it
+    // as an input, or for the palette indices its quantize filter leaves
+    pub fn wants_canvas(&self, name: &str, filters: &[Filter]) -> bool {
+        self.graph.has_dependents(name) || quantizes(filters)
+    }
+
+    // makes the pixels available to textures that use this one as an input
+    pub fn publish_canvas(&mut self, name: &str, canvas: Canvas) {
+        self.canvas_version += 1;
+        self.canvases
+            .insert(name.to_string(), (canvas, self.canvas_version));
+    }
+
+    // gives the texture called name another descriptor's settings, keeping its name and size
+    pub fn replace_descriptor(&mut self, name: &str, desc: &RenderToTextureDescriptor) {
+        if let Some((_, (_, d))) = self.list.iter_mut().find(|(_, (_, d))| d.name == name) {
+            *d = RenderToTextureDescriptor {
+                name: d.name,
+                size: d.size,
+                ..*desc
+            };
+        }
+    }
+
     fn add_dynamic_texture(
         &mut self,
         descriptor: &RenderToTextureDescriptor,
@@ -119,6 +357,13 @@ impl DynamicTextures {
             None
         }
     }
+
+    // gives back a layer that was just handed out and never used
+    fn release_render_layer(&mut self, layer: u8) {
+        if layer == self.highest_render_layer {
+            self.highest_render_layer -= 1;
+        }
+    }
 }
 
 // takes RenderToTextureDescriptor and uses its info to add camera and image it will render to to a render layer of its own
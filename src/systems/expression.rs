@@ -0,0 +1,511 @@
+use crate::systems::patterns::value_noise;
+
+// the variables a formula can use, in the order their values are passed to Expr::eval:
+// the texture's clock time, the circle's radius, its position, its placement index, its
+// color's hue (degrees), saturation and lightness, and the audio levels in AudioInput
+pub const VARIABLES: [&str; 12] = [
+    "t", "r", "x", "y", "i", "hue", "sat", "light", "amp", "bass", "mid", "treble",
+];
+
+#[derive(Debug)]
+pub struct ExprError {
+    // 0-based byte offset into the source; the tokenizer stops at the first character that isn't
+    // ASCII, so it's also the column
+    pub pos: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for ExprError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "column {}: {}", self.pos + 1, self.message)
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+impl ExprError {
+    // the error with the source underneath and a caret at the position
+    pub fn annotate(&self, source: &str) -> String {
+        format!("{}\n  {}\n  {}^", self, source, " ".repeat(self.pos))
+    }
+}
+
+fn expr_error(pos: usize, message: impl Into<String>) -> ExprError {
+    ExprError {
+        pos,
+        message: message.into(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Func {
+    Sin,
+    Cos,
+    Tan,
+    Abs,
+    Sqrt,
+    Exp,
+    Floor,
+    Fract,
+    // smooth value noise in 0.0..1.0
+    Noise,
+    Min,
+    Max,
+    Pow,
+    // 0.0 below the edge, 1.0 from it on: step(edge, x)
+    Step,
+    Clamp,
+    // mix(a, b, t), a linear blend
+    Mix,
+}
+
+impl Func {
+    fn from_name(name: &str) -> Option<Func> {
+        Some(match name {
+            "sin" => Func::Sin,
+            "cos" => Func::Cos,
+            "tan" => Func::Tan,
+            "abs" => Func::Abs,
+            "sqrt" => Func::Sqrt,
+            "exp" => Func::Exp,
+            "floor" => Func::Floor,
+            "fract" => Func::Fract,
+            "noise" => Func::Noise,
+            "min" => Func::Min,
+            "max" => Func::Max,
+            "pow" => Func::Pow,
+            "step" => Func::Step,
+            "clamp" => Func::Clamp,
+            "mix" => Func::Mix,
+            _ => return None,
+        })
+    }
+
+    fn arity(self) -> usize {
+        match self {
+            Func::Sin
+            | Func::Cos
+            | Func::Tan
+            | Func::Abs
+            | Func::Sqrt
+            | Func::Exp
+            | Func::Floor
+            | Func::Fract
+            | Func::Noise => 1,
+            Func::Min | Func::Max | Func::Pow | Func::Step => 2,
+            Func::Clamp | Func::Mix => 3,
+        }
+    }
+
+    fn apply(self, a: &[f32]) -> f32 {
+        match self {
+            Func::Sin => a[0].sin(),
+            Func::Cos => a[0].cos(),
+            Func::Tan => a[0].tan(),
+            Func::Abs => a[0].abs(),
+            Func::Sqrt => a[0].sqrt(),
+            Func::Exp => a[0].exp(),
+            Func::Floor => a[0].floor(),
+            Func::Fract => a[0].rem_euclid(1.0),
+            Func::Noise => value_noise(a[0], 0.0, 0x5EED),
+            Func::Min => a[0].min(a[1]),
+            Func::Max => a[0].max(a[1]),
+            Func::Pow => a[0].powf(a[1]),
+            Func::Step => {
+                if a[1] < a[0] {
+                    0.0
+                } else {
+                    1.0
+                }
+            }
+            // not f32::clamp, which panics when the bounds are the wrong way round
+            Func::Clamp => a[0].max(a[1]).min(a[2]),
+            Func::Mix => a[0] + (a[1] - a[0]) * a[2],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Num(f32),
+    // index into VARIABLES
+    Var(usize),
+    Neg(Box<Node>),
+    Binary(Op, Box<Node>, Box<Node>),
+    Call(Func, Vec<Node>),
+}
+
+// a parsed formula, ready to evaluate many times
+#[derive(Clone, Debug)]
+pub struct Expr {
+    root: Node,
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr, ExprError> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            next: 0,
+            end: source.len(),
+        };
+        let root = parser.expr()?;
+        match parser.peek() {
+            None => Ok(Expr { root }),
+            Some((pos, token)) => Err(expr_error(
+                pos,
+                format!("expected an operator, found {}", token.describe()),
+            )),
+        }
+    }
+
+    // vars holds the values of VARIABLES, in order
+    pub fn eval(&self, vars: &[f32; VARIABLES.len()]) -> f32 {
+        self.root.eval(vars)
+    }
+}
+
+impl Node {
+    fn eval(&self, vars: &[f32; VARIABLES.len()]) -> f32 {
+        match self {
+            Node::Num(n) => *n,
+            Node::Var(v) => vars[*v],
+            Node::Neg(e) => -e.eval(vars),
+            Node::Binary(op, a, b) => {
+                let (a, b) = (a.eval(vars), b.eval(vars));
+                match op {
+                    Op::Add => a + b,
+                    Op::Sub => a - b,
+                    Op::Mul => a * b,
+                    Op::Div => a / b,
+                    Op::Rem => a.rem_euclid(b),
+                    Op::Pow => a.powf(b),
+                }
+            }
+            Node::Call(func, args) => {
+                let mut values = [0.0; 3];
+                for (value, arg) in values.iter_mut().zip(args) {
+                    *value = arg.eval(vars);
+                }
+                func.apply(&values)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(f32),
+    Ident(String),
+    Op(char),
+    Open,
+    Close,
+    Comma,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Num(n) => format!("number {n}"),
+            Token::Ident(name) => format!("\"{name}\""),
+            Token::Op(c) => format!("\"{c}\""),
+            Token::Open => "\"(\"".to_string(),
+            Token::Close => "\")\"".to_string(),
+            Token::Comma => "\",\"".to_string(),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ExprError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(pos, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut end = pos;
+            while let Some(&(i, c)) = chars.peek() {
+                let exponent_sign = (c == '-' || c == '+') && source[..i].ends_with(['e', 'E']);
+                if !(c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || exponent_sign) {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            let text = &source[pos..end];
+            let n = text
+                .parse()
+                .map_err(|_| expr_error(pos, format!("bad number \"{text}\"")))?;
+            tokens.push((pos, Token::Num(n)));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut end = pos;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            tokens.push((pos, Token::Ident(source[pos..end].to_string())));
+        } else {
+            let token = match c {
+                '+' | '-' | '*' | '/' | '%' | '^' => Token::Op(c),
+                '(' => Token::Open,
+                ')' => Token::Close,
+                ',' => Token::Comma,
+                _ => return Err(expr_error(pos, format!("unexpected \"{c}\""))),
+            };
+            tokens.push((pos, token));
+            chars.next();
+        }
+    }
+    Ok(tokens)
+}
+
+// recursive descent, lowest precedence first: + -, then * / %, then unary minus, then ^
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    // reported as the position of errors at the end of the source
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<(usize, &Token)> {
+        self.tokens.get(self.next).map(|(pos, token)| (*pos, token))
+    }
+
+    fn advance(&mut self) -> Option<(usize, Token)> {
+        let token = self.tokens.get(self.next).cloned();
+        self.next += 1;
+        token
+    }
+
+    fn take_op(&mut self, ops: &[char]) -> Option<char> {
+        match self.peek() {
+            Some((_, Token::Op(c))) if ops.contains(c) => {
+                let c = *c;
+                self.next += 1;
+                Some(c)
+            }
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, expected: &Token) -> Result<(), ExprError> {
+        match self.advance() {
+            Some((_, token)) if token == *expected => Ok(()),
+            Some((pos, token)) => Err(expr_error(
+                pos,
+                format!(
+                    "expected {}, found {}",
+                    expected.describe(),
+                    token.describe()
+                ),
+            )),
+            None => Err(expr_error(
+                self.end,
+                format!("expected {}, found the end", expected.describe()),
+            )),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Node, ExprError> {
+        let mut left = self.term()?;
+        while let Some(c) = self.take_op(&['+', '-']) {
+            let op = if c == '+' { Op::Add } else { Op::Sub };
+            left = Node::Binary(op, Box::new(left), Box::new(self.term()?));
+        }
+        Ok(left)
+    }
+
+    fn term(&mut self) -> Result<Node, ExprError> {
+        let mut left = self.unary()?;
+        while let Some(c) = self.take_op(&['*', '/', '%']) {
+            let op = match c {
+                '*' => Op::Mul,
+                '/' => Op::Div,
+                _ => Op::Rem,
+            };
+            left = Node::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Node, ExprError> {
+        if self.take_op(&['-']).is_some() {
+            return Ok(Node::Neg(Box::new(self.unary()?)));
+        }
+        self.power()
+    }
+
+    // right associative, and binds tighter than unary minus: -2^2 is -4
+    fn power(&mut self) -> Result<Node, ExprError> {
+        let base = self.atom()?;
+        if self.take_op(&['^']).is_some() {
+            return Ok(Node::Binary(
+                Op::Pow,
+                Box::new(base),
+                Box::new(self.unary()?),
+            ));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<Node, ExprError> {
+        match self.advance() {
+            Some((_, Token::Num(n))) => Ok(Node::Num(n)),
+            Some((_, Token::Open)) => {
+                let inner = self.expr()?;
+                self.expect(&Token::Close)?;
+                Ok(inner)
+            }
+            Some((pos, Token::Ident(name))) => {
+                if matches!(self.peek(), Some((_, Token::Open))) {
+                    self.call(pos, &name)
+                } else if name == "pi" {
+                    Ok(Node::Num(std::f32::consts::PI))
+                } else if let Some(v) = VARIABLES.iter().position(|v| *v == name) {
+                    Ok(Node::Var(v))
+                } else {
+                    Err(expr_error(
+                        pos,
+                        format!(
+                            "unknown variable \"{name}\"; expected one of {}",
+                            VARIABLES.join(", ")
+                        ),
+                    ))
+                }
+            }
+            Some((pos, token)) => Err(expr_error(
+                pos,
+                format!("expected a value, found {}", token.describe()),
+            )),
+            None => Err(expr_error(self.end, "expected a value, found the end")),
+        }
+    }
+
+    fn call(&mut self, pos: usize, name: &str) -> Result<Node, ExprError> {
+        let func = Func::from_name(name)
+            .ok_or_else(|| expr_error(pos, format!("unknown function \"{name}\"")))?;
+        self.expect(&Token::Open)?;
+        let mut args = Vec::new();
+        if !matches!(self.peek(), Some((_, Token::Close))) {
+            args.push(self.expr()?);
+            while matches!(self.peek(), Some((_, Token::Comma))) {
+                self.next += 1;
+                args.push(self.expr()?);
+            }
+        }
+        self.expect(&Token::Close)?;
+        if args.len() != func.arity() {
+            return Err(expr_error(
+                pos,
+                format!(
+                    "{name} takes {} arguments, not {}",
+                    func.arity(),
+                    args.len()
+                ),
+            ));
+        }
+        Ok(Node::Call(func, args))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str, vars: &[f32; VARIABLES.len()]) -> f32 {
+        Expr::parse(source).unwrap().eval(vars)
+    }
+
+    fn assert_evals(source: &str, expected: f32) {
+        let value = eval(source, &[0.0; VARIABLES.len()]);
+        assert!(
+            (value - expected).abs() < 1e-5,
+            "{source} gave {value}, not {expected}"
+        );
+    }
+
+    fn error_of(source: &str) -> (usize, String) {
+        let err = Expr::parse(source).unwrap_err();
+        (err.pos, err.message)
+    }
+
+    #[test]
+    fn follows_precedence_and_associativity() {
+        assert_evals("1 + 2 * 3", 7.0);
+        assert_evals("(1 + 2) * 3", 9.0);
+        assert_evals("10 - 4 - 3", 3.0);
+        assert_evals("-2^2", -4.0);
+        assert_evals("2^3^2", 512.0);
+        assert_evals("2^-1", 0.5);
+        assert_evals("-7 % 3", 2.0);
+        assert_evals("1.5e1 + 2E-1", 15.2);
+    }
+
+    #[test]
+    fn calls_functions() {
+        assert_evals("clamp(5, 0, 1)", 1.0);
+        assert_evals("mix(0, 10, 0.25)", 2.5);
+        assert_evals("step(1, 0.5) + step(1, 1)", 1.0);
+        assert_evals("max(min(3, 4), 2) + fract(-0.25)", 3.75);
+        assert_evals("cos(pi)", -1.0);
+    }
+
+    #[test]
+    fn reads_variables_in_order() {
+        let mut vars = [0.0; VARIABLES.len()];
+        for (i, v) in vars.iter_mut().enumerate() {
+            *v = i as f32;
+        }
+        assert!((eval("t + r * 10 + treble * 100", &vars) - 1110.0).abs() < 1e-3);
+        assert!((eval("hue", &vars) - 5.0).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn reports_where_parsing_failed() {
+        assert_eq!(
+            error_of("1 +"),
+            (3, "expected a value, found the end".to_string())
+        );
+        assert_eq!(
+            error_of("2 3"),
+            (2, "expected an operator, found number 3".to_string())
+        );
+        assert_eq!(error_of("1 $ 2"), (2, "unexpected \"$\"".to_string()));
+        assert_eq!(
+            error_of("sin(1, 2)"),
+            (0, "sin takes 1 arguments, not 2".to_string())
+        );
+        assert_eq!(
+            error_of("t * wobble(t)"),
+            (4, "unknown function \"wobble\"".to_string())
+        );
+        assert_eq!(error_of("(1 + 2").0, 6);
+        assert!(error_of("speed")
+            .1
+            .starts_with("unknown variable \"speed\""));
+        // columns count characters before the error, which are all ASCII
+        assert_eq!(error_of("x + é").0, 4);
+    }
+
+    #[test]
+    fn annotates_the_source_with_a_caret() {
+        let err = Expr::parse("x + )").unwrap_err();
+        assert_eq!(
+            err.annotate("x + )"),
+            "column 5: expected a value, found \")\"\n  x + )\n      ^"
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use bevy::math::Vec2;
+use bevy::render::color::Color;
+use palette::{FromColor, Hsl, Srgb};
+
+use crate::systems::canvas::Canvas;
+use crate::systems::quantize::{quantize, Dither, NearestPalette};
+
+// post-processing applied, in order, to a generated texture
+#[derive(Clone, Copy, Debug)]
+pub enum Filter {
+    // standard deviation in pixels
+    GaussianBlur {
+        sigma: f32,
+    },
+    // blurs the parts brighter than threshold and adds them back on top
+    Bloom {
+        threshold: f32,
+        intensity: f32,
+        sigma: f32,
+    },
+    // darkens toward the corners, starting at radius (0.0 is the center, 1.0 a corner)
+    Vignette {
+        strength: f32,
+        radius: f32,
+    },
+    Posterize {
+        levels: u32,
+    },
+    Pixelate {
+        block: u32,
+    },
+    // draws color wherever the luminance or alpha changes by more than threshold
+    EdgeOutline {
+        color: Color,
+        threshold: f32,
+    },
+    HueShift {
+        degrees: f32,
+    },
+    // limits the texture to the palette's colors; see quantize()
+    Quantize {
+        palette: &'static [Color],
+        dither: Dither,
+    },
+}
+
+impl Filter {
+    // whether the filter only looks at one pixel at a time, so it can be applied to the
+    // circle colors of textures rendered on the GPU
+    pub fn is_per_color(&self) -> bool {
+        matches!(
+            self,
+            Filter::Posterize { .. }
+                | Filter::HueShift { .. }
+                | Filter::Vignette { .. }
+                | Filter::Quantize {
+                    dither: Dither::None,
+                    ..
+                }
+        )
+    }
+
+    // applies a per-color filter to one color; pos is where the color is relative to the
+    // texture's center, in units of half the texture's diagonal. Quantize is left to ColorFilters,
+    // which keeps its palette ready
+    pub fn apply_to_color(&self, c: [f32; 4], pos: Vec2) -> [f32; 4] {
+        match *self {
+            Filter::Posterize { levels } => {
+                let steps = (levels.max(2) - 1) as f32;
+                [
+                    (c[0] * steps).round() / steps,
+                    (c[1] * steps).round() / steps,
+                    (c[2] * steps).round() / steps,
+                    c[3],
+                ]
+            }
+            Filter::HueShift { degrees } => {
+                let mut hsl = Hsl::from_color(Srgb::new(c[0], c[1], c[2]));
+                hsl.hue += degrees;
+                let rgb = Srgb::from_color(hsl);
+                [rgb.red, rgb.green, rgb.blue, c[3]]
+            }
+            Filter::Vignette { strength, radius } => {
+                let d = pos.length();
+                let t = ((d - radius) / (1.0 - radius).max(f32::EPSILON)).clamp(0.0, 1.0);
+                let k = 1.0 - strength * t * t * (3.0 - 2.0 * t);
+                [c[0] * k, c[1] * k, c[2] * k, c[3]]
+            }
+            _ => c,
+        }
+    }
+
+    pub fn apply(&self, canvas: &mut Canvas) {
+        match *self {
+            Filter::GaussianBlur { sigma } => gaussian_blur(canvas, sigma),
+            Filter::Bloom {
+                threshold,
+                intensity,
+                sigma,
+            } => {
+                let mut bright = canvas.clone();
+                for p in &mut bright.pixels {
+                    if luminance(*p) < threshold {
+                        *p = [0.0, 0.0, 0.0, p[3]];
+                    }
+                }
+                gaussian_blur(&mut bright, sigma);
+                for (p, b) in canvas.pixels.iter_mut().zip(&bright.pixels) {
+                    for ch in 0..3 {
+                        p[ch] = (p[ch] + b[ch] * intensity).min(1.0);
+                    }
+                }
+            }
+            Filter::Pixelate { block } => pixelate(canvas, block.max(1)),
+            Filter::EdgeOutline { color, threshold } => edge_outline(canvas, color, threshold),
+            Filter::Quantize { palette, dither } => quantize(canvas, palette, dither),
+            Filter::Posterize { .. } | Filter::HueShift { .. } | Filter::Vignette { .. } => {
+                let half = Vec2::new(canvas.width as f32, canvas.height as f32) / 2.0;
+                let scale = half.length().max(f32::EPSILON);
+                for y in 0..canvas.height {
+                    for x in 0..canvas.width {
+                        let pos = (Vec2::new(x as f32 + 0.5, y as f32 + 0.5) - half) / scale;
+                        let c = self.apply_to_color(canvas.get(x, y), pos);
+                        canvas.set(x, y, c);
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn apply_filters(canvas: &mut Canvas, filters: &[Filter]) {
+    for filter in filters {
+        canvas.palette_indices = None;
+        filter.apply(canvas);
+    }
+}
+
+// whether a texture drawn with circle meshes has to be drawn on the CPU instead, because some of
+// its filters look at more than one pixel
+pub fn needs_canvas(filters: &[Filter]) -> bool {
+    filters.iter().any(|f| !f.is_per_color())
+}
+
+// whether the filters leave palette indices on the canvas, for DynamicTextures::palette_indices
+pub fn quantizes(filters: &[Filter]) -> bool {
+    filters.iter().any(|f| matches!(f, Filter::Quantize { .. }))
+}
+
+// a texture's per-color filters, ready to apply to its circle colors every frame
+pub struct ColorFilters {
+    filters: &'static [Filter],
+    // the palette of each Quantize filter, matched against without converting it again
+    palettes: Vec<Option<NearestPalette>>,
+}
+
+impl ColorFilters {
+    pub fn new(filters: &'static [Filter]) -> ColorFilters {
+        ColorFilters {
+            filters,
+            palettes: filters
+                .iter()
+                .map(|f| match f {
+                    Filter::Quantize { palette, .. } => Some(NearestPalette::new(palette)),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    // applies them to a circle color at pos, in world units from the center of a texture size
+    // pixels across
+    pub fn apply(&self, color: Color, pos: Vec2, size: u32) -> Color {
+        let mut c = color.as_rgba_f32();
+        // world y points up, which doesn't matter for the radially symmetric vignette
+        let pos = pos / (Vec2::splat(size as f32 / 2.0).length().max(f32::EPSILON));
+        for (filter, palette) in self.filters.iter().zip(&self.palettes) {
+            if !filter.is_per_color() {
+                continue;
+            }
+            c = match palette {
+                Some(palette) => palette
+                    .nearest(Color::rgba(c[0], c[1], c[2], c[3]))
+                    .as_rgba_f32(),
+                None => filter.apply_to_color(c, pos),
+            };
+        }
+        Color::rgba(c[0], c[1], c[2], c[3])
+    }
+}
+
+fn luminance(p: [f32; 4]) -> f32 {
+    0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2]
+}
+
+fn gaussian_blur(canvas: &mut Canvas, sigma: f32) {
+    if sigma <= 0.0 {
+        return;
+    }
+    let half = (sigma * 3.0).ceil() as i32;
+    let mut kernel: Vec<f32> = (-half..=half)
+        .map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    for k in &mut kernel {
+        *k /= sum;
+    }
+    let (w, h) = (canvas.width as i32, canvas.height as i32);
+    // horizontal then vertical pass, clamping at the edges
+    for (dx, dy) in [(1, 0), (0, 1)] {
+        let source = canvas.clone();
+        for y in 0..h {
+            for x in 0..w {
+                let mut acc = [0.0; 4];
+                for (i, k) in (-half..=half).zip(&kernel) {
+                    let sx = (x + i * dx).clamp(0, w - 1);
+                    let sy = (y + i * dy).clamp(0, h - 1);
+                    let p = source.get(sx as u32, sy as u32);
+                    for ch in 0..4 {
+                        acc[ch] += p[ch] * k;
+                    }
+                }
+                canvas.set(x as u32, y as u32, acc);
+            }
+        }
+    }
+}
+
+fn pixelate(canvas: &mut Canvas, block: u32) {
+    for by in (0..canvas.height).step_by(block as usize) {
+        for bx in (0..canvas.width).step_by(block as usize) {
+            let (x1, y1) = (
+                (bx + block).min(canvas.width),
+                (by + block).min(canvas.height),
+            );
+            let mut acc = [0.0; 4];
+            for y in by..y1 {
+                for x in bx..x1 {
+                    let p = canvas.get(x, y);
+                    for ch in 0..4 {
+                        acc[ch] += p[ch];
+                    }
+                }
+            }
+            let n = ((x1 - bx) * (y1 - by)) as f32;
+            let avg = acc.map(|a| a / n);
+            for y in by..y1 {
+                for x in bx..x1 {
+                    canvas.set(x, y, avg);
+                }
+            }
+        }
+    }
+}
+
+fn edge_outline(canvas: &mut Canvas, color: Color, threshold: f32) {
+    let source = canvas.clone();
+    let (w, h) = (canvas.width as i32, canvas.height as i32);
+    let value = |x: i32, y: i32| {
+        let p = source.get(x.clamp(0, w - 1) as u32, y.clamp(0, h - 1) as u32);
+        luminance(p) * p[3] + p[3]
+    };
+    let outline = color.as_rgba_f32();
+    for y in 0..h {
+        for x in 0..w {
+            // sobel operator
+            let gx = value(x + 1, y - 1) + 2.0 * value(x + 1, y) + value(x + 1, y + 1)
+                - value(x - 1, y - 1)
+                - 2.0 * value(x - 1, y)
+                - value(x - 1, y + 1);
+            let gy = value(x - 1, y + 1) + 2.0 * value(x, y + 1) + value(x + 1, y + 1)
+                - value(x - 1, y - 1)
+                - 2.0 * value(x, y - 1)
+                - value(x + 1, y - 1);
+            if (gx * gx + gy * gy).sqrt() > threshold {
+                canvas.paint(x as u32, y as u32, outline, 1.0);
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use bevy::asset::{
+    AssetEvent, AssetLoader, AssetServer, Assets, BoxedFuture, Handle, LoadContext, LoadState,
+    LoadedAsset,
+};
+use bevy::ecs::{
+    component::Component,
+    event::EventReader,
+    system::{Query, Res},
+};
+use bevy::log::error;
+use bevy::reflect::TypeUuid;
+
+use crate::systems::animation::{parse_formulas, Formula, ModTarget};
+use crate::systems::circles::Circles2;
+
+// a Circles2 texture's formulas, loaded from a .formulas file: '#' comments and one
+// "target = expression" per line, where target is r, x, y, hue, sat, light or alpha
+#[derive(Clone, Debug, Default, TypeUuid)]
+#[uuid = "0c5e8f3d-2b7a-4e61-9d48-a1f6c3b2e745"]
+pub struct FormulaSheet {
+    pub formulas: Vec<Formula>,
+}
+
+#[derive(Debug)]
+pub struct FormulaParseError {
+    // 1-based
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for FormulaParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for FormulaParseError {}
+
+fn parse_error(line: usize, message: impl Into<String>) -> FormulaParseError {
+    FormulaParseError {
+        line: line + 1,
+        message: message.into(),
+    }
+}
+
+impl FormulaSheet {
+    // only the layout is checked here; parse_formulas reports expressions that don't parse
+    pub fn parse(text: &str) -> Result<FormulaSheet, FormulaParseError> {
+        let mut sheet = FormulaSheet::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (target, expr) = line
+                .split_once('=')
+                .ok_or_else(|| parse_error(n, "expected \"target = expression\""))?;
+            let target = match target.trim() {
+                "r" => ModTarget::Radius,
+                "x" => ModTarget::PositionX,
+                "y" => ModTarget::PositionY,
+                "hue" => ModTarget::Hue,
+                "sat" => ModTarget::Saturation,
+                "light" => ModTarget::Lightness,
+                "alpha" => ModTarget::Alpha,
+                other => return Err(parse_error(n, format!("unknown target \"{other}\""))),
+            };
+            let expr = expr.trim();
+            if expr.is_empty() {
+                return Err(parse_error(n, "missing expression"));
+            }
+            sheet.formulas.push(Formula {
+                target,
+                expr: expr.to_string(),
+                line: n + 1,
+            });
+        }
+        Ok(sheet)
+    }
+}
+
+#[derive(Default)]
+pub struct FormulaSheetLoader;
+
+impl AssetLoader for FormulaSheetLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let sheet = FormulaSheet::parse(std::str::from_utf8(bytes)?)?;
+            load_context.set_default_asset(LoadedAsset::new(sheet));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["formulas"]
+    }
+}
+
+// the formula sheet of a Circles2 generator entity; the circles animate without formulas until
+// it loads, and pick up changes to the file when it's reloaded
+#[derive(Component)]
+pub struct TextureFormulas {
+    pub sheet: Handle<FormulaSheet>,
+    applied: bool,
+}
+
+impl TextureFormulas {
+    pub fn new(sheet: Handle<FormulaSheet>) -> TextureFormulas {
+        TextureFormulas {
+            sheet,
+            applied: false,
+        }
+    }
+}
+
+pub fn apply_formula_sheets(
+    mut events: EventReader<AssetEvent<FormulaSheet>>,
+    asset_server: Res<AssetServer>,
+    sheets: Res<Assets<FormulaSheet>>,
+    mut query: Query<(&mut Circles2, &mut TextureFormulas)>,
+) {
+    let modified: Vec<Handle<FormulaSheet>> = events
+        .iter()
+        .filter_map(|e| match e {
+            AssetEvent::Modified { handle } => Some(handle.clone_weak()),
+            _ => None,
+        })
+        .collect();
+    for (mut circles2, mut formulas) in &mut query {
+        if modified.contains(&formulas.sheet) {
+            formulas.applied = false;
+        }
+        if formulas.applied {
+            continue;
+        }
+        if let Some(sheet) = sheets.get(&formulas.sheet) {
+            let parsed = parse_formulas(circles2.name, &sheet.formulas);
+            circles2.set_formulas(parsed);
+            formulas.applied = true;
+        } else if asset_server.get_load_state(&formulas.sheet) == LoadState::Failed {
+            error!(
+                "{}: couldn't load formulas; animating without them",
+                circles2.name
+            );
+            formulas.applied = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_targets_and_skips_comments() {
+        let sheet =
+            FormulaSheet::parse("# wobble\nr = 1 + 0.1 * sin(t)\n\nhue=t*10 # spin\n").unwrap();
+        assert_eq!(
+            sheet.formulas,
+            vec![
+                Formula {
+                    target: ModTarget::Radius,
+                    expr: "1 + 0.1 * sin(t)".to_string(),
+                    line: 2,
+                },
+                Formula {
+                    target: ModTarget::Hue,
+                    expr: "t*10".to_string(),
+                    line: 4,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_give_the_line() {
+        let err = FormulaSheet::parse("r = t\nsize = 2\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: unknown target \"size\"");
+        let err = FormulaSheet::parse("\n\nx t\n").unwrap_err();
+        assert_eq!(err.line, 3);
+        let err = FormulaSheet::parse("y = # nothing\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: missing expression");
+    }
+}
@@ -0,0 +1,215 @@
+use bevy::asset::{Assets, Handle};
+use bevy::ecs::{
+    component::Component,
+    query::Without,
+    system::{Commands, Query, ResMut},
+};
+use bevy::log::{error, info};
+use bevy::render::{color::Color, texture::Image};
+
+use crate::systems::automaton::{AutomatonParams, AutomatonRule, CellGrid};
+use crate::systems::canvas::{spawn_canvas_sprite, Canvas};
+use crate::systems::circles::render_packed_circles;
+use crate::systems::color_generator::{ColorGenerator, ColorOptions};
+use crate::systems::color_palette::{ColorPalette, PendingPalette, TexturePalette};
+use crate::systems::dynamic_textures::{
+    DynamicTextures, GeneratorParams, RenderToTextureDescriptor, StartColor,
+};
+use crate::systems::filters::apply_filters;
+use crate::systems::patterns::{render_noise, render_stipple, NoiseParams, StippleParams};
+use crate::systems::reaction_diffusion::{GrayScott, GrayScottParams};
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Add,
+}
+
+impl BlendMode {
+    // blends one channel of the layer (s) onto one channel of what is below it (d)
+    pub fn blend(self, d: f32, s: f32) -> f32 {
+        match self {
+            BlendMode::Normal => s,
+            BlendMode::Multiply => d * s,
+            BlendMode::Screen => 1.0 - (1.0 - d) * (1.0 - s),
+            BlendMode::Overlay => {
+                if d < 0.5 {
+                    2.0 * d * s
+                } else {
+                    1.0 - 2.0 * (1.0 - d) * (1.0 - s)
+                }
+            }
+            BlendMode::Add => (d + s).min(1.0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TextureLayer {
+    pub functype: &'static str,
+    pub params: GeneratorParams,
+    // falls back to the descriptor's start color
+    pub start_color: Option<StartColor>,
+    pub blend: BlendMode,
+    pub opacity: f32,
+}
+
+impl TextureLayer {
+    pub const fn new(functype: &'static str, blend: BlendMode, opacity: f32) -> TextureLayer {
+        TextureLayer {
+            functype,
+            params: GeneratorParams::Default,
+            start_color: None,
+            blend,
+            opacity,
+        }
+    }
+}
+
+// renders one generator onto a canvas on the CPU; patterns meant as overlays leave the rest transparent
+pub fn render_generator(
+    functype: &str,
+    params: GeneratorParams,
+    start_color: &StartColor,
+    color: ColorOptions,
+    palette: Option<&ColorPalette>,
+    size: u32,
+) -> Option<Canvas> {
+    let (generator, _) = ColorGenerator::from_start_color(start_color, color, palette);
+    let mut canvas = Canvas::new(size, size, Color::NONE.as_rgba_f32());
+    match functype {
+        "Circles1" | "Circles2" => {
+            let adjusted = render_packed_circles(
+                start_color,
+                color,
+                palette,
+                &mut canvas,
+                &mut rand::thread_rng(),
+            );
+            if adjusted > 0 {
+                info!("{functype}: adjusted {adjusted} colors to keep the minimum contrast");
+            }
+        }
+        "ReactionDiffusion" => {
+            let params = match params {
+                GeneratorParams::GrayScott(params) => params,
+                _ => GrayScottParams::default(),
+            };
+            let mut sim = GrayScott::new(size, params);
+            sim.run(params.warmup_steps);
+            sim.render(&generator, Color::NONE, &mut canvas);
+        }
+        "Automaton" => {
+            let params = match params {
+                GeneratorParams::Automaton(params) => params,
+                _ => AutomatonParams::default(),
+            };
+            let rule = match params.rule.parse::<AutomatonRule>() {
+                Ok(rule) => rule,
+                Err(e) => {
+                    error!("bad automaton rule \"{}\": {}", params.rule, e);
+                    return None;
+                }
+            };
+            let cell_size = params.cell_size.max(1);
+            let cells = (size / cell_size).max(1);
+            let mut grid = CellGrid::new(cells, cells, rule, params.density, params.seed);
+            grid.run(params.generations);
+            grid.render(&generator, Color::NONE, cell_size, &mut canvas);
+        }
+        "Noise" => {
+            let params = match params {
+                GeneratorParams::Noise(params) => params,
+                _ => NoiseParams::default(),
+            };
+            render_noise(&params, &generator, &mut canvas);
+        }
+        "Stipple" => {
+            let params = match params {
+                GeneratorParams::Stipple(params) => params,
+                _ => StippleParams::default(),
+            };
+            render_stipple(&params, &generator, &mut canvas);
+        }
+        _ => {
+            error!("{} can't be used as a texture layer", functype);
+            return None;
+        }
+    }
+    Some(canvas)
+}
+
+// composites the layer onto the destination with the given blend mode and opacity
+pub fn composite(dest: &mut Canvas, layer: &Canvas, blend: BlendMode, opacity: f32) {
+    for (d, s) in dest.pixels.iter_mut().zip(&layer.pixels) {
+        let alpha = s[3] * opacity.clamp(0.0, 1.0);
+        if alpha <= 0.0 {
+            continue;
+        }
+        for ch in 0..3 {
+            let blended = blend.blend(d[ch], s[ch]);
+            d[ch] += (blended - d[ch]) * alpha;
+        }
+        d[3] += alpha * (1.0 - d[3]);
+    }
+}
+
+// renders every layer of the descriptor over its background color
+pub fn render_layers(desc: &RenderToTextureDescriptor, palette: Option<&ColorPalette>) -> Canvas {
+    let mut canvas = Canvas::new(desc.size, desc.size, desc.background_color.as_rgba_f32());
+    for layer in desc.layers {
+        let start_color = layer.start_color.unwrap_or(desc.start_color);
+        if let Some(rendered) = render_generator(
+            layer.functype,
+            layer.params,
+            &start_color,
+            desc.color,
+            palette,
+            desc.size,
+        ) {
+            composite(&mut canvas, &rendered, layer.blend, layer.opacity);
+        }
+    }
+    canvas
+}
+
+#[derive(Component)]
+pub struct LayeredTexture {
+    pub layer: u8,
+    pub descriptor: RenderToTextureDescriptor,
+    image: Handle<Image>,
+    done_setup: bool,
+}
+
+impl LayeredTexture {
+    pub fn new(layer: u8, desc: &RenderToTextureDescriptor) -> LayeredTexture {
+        LayeredTexture {
+            layer,
+            descriptor: *desc,
+            image: Handle::default(),
+            done_setup: false,
+        }
+    }
+}
+
+pub fn layered_texture_setup(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut dyntex: ResMut<DynamicTextures>,
+    mut query: Query<(&mut LayeredTexture, Option<&TexturePalette>), Without<PendingPalette>>,
+) {
+    for (mut layered, palette) in &mut query {
+        if layered.done_setup {
+            continue;
+        }
+        let mut canvas = render_layers(&layered.descriptor, palette.map(|p| &p.0));
+        apply_filters(&mut canvas, layered.descriptor.filters);
+        layered.image = spawn_canvas_sprite(&mut commands, &mut images, &canvas, layered.layer);
+        dyntex.publish_canvas(layered.descriptor.name, canvas);
+        layered.done_setup = true;
+    }
+}
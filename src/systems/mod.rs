@@ -1,4 +1,25 @@
+pub mod animation;
+pub mod audio;
+pub mod automaton;
+pub mod bench;
+pub mod canvas;
 pub mod circles;
+pub mod clock;
 pub mod color_generator;
+pub mod color_palette;
+pub mod color_vision;
 pub mod dynamic_textures;
+pub mod expression;
+pub mod filters;
+pub mod formula_sheet;
+pub mod layers;
+pub mod morph;
+pub mod motion;
+pub mod palette_extraction;
+pub mod patterns;
+pub mod physics;
+pub mod quantize;
+pub mod reaction_diffusion;
+pub mod reactions;
 pub mod screenshot;
+pub mod texture_graph;
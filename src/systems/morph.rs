@@ -0,0 +1,372 @@
+use bevy::asset::{AssetServer, Assets};
+use bevy::core_pipeline::{clear_color::ClearColorConfig, core_2d::Camera2d};
+use bevy::ecs::{
+    component::Component,
+    entity::Entity,
+    event::EventReader,
+    query::{With, Without},
+    system::{Commands, Query, Res, ResMut},
+};
+use bevy::log::warn;
+use bevy::math::{Vec2, Vec3};
+use bevy::render::{color::Color, texture::Image, view::RenderLayers};
+use bevy::sprite::{ColorMaterial, MaterialMesh2dBundle};
+use bevy::transform::components::Transform;
+use bevy::utils::default;
+use palette::{FromColor, Mix, Oklab, Srgb};
+
+use crate::systems::animation::Easing;
+use crate::systems::circles::{
+    circles_canvas, pack_circles, report_adjusted, AllCircles, CircleMesh, Circles2, TextureCircle,
+};
+use crate::systems::clock::TextureClock;
+use crate::systems::color_palette::TexturePalette;
+use crate::systems::dynamic_textures::{
+    DynamicTextures, RenderToTextureDescriptor, RenderToTexturePass,
+};
+use crate::systems::formula_sheet::TextureFormulas;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MorphMatching {
+    // each circle goes to the closest free circle of the new layout, biggest circles first
+    #[default]
+    Nearest,
+    // the nth biggest circle goes to the nth biggest of the new layout
+    SizeRank,
+}
+
+// changes a Circles2 texture into another descriptor's look, in place, so everything using its
+// image sees the transition; the texture keeps its name, layer and size
+pub struct MorphTextureEvent {
+    pub name: String,
+    pub to: RenderToTextureDescriptor,
+    pub matching: MorphMatching,
+    // seconds on the texture's clock
+    pub duration: f32,
+    pub easing: Easing,
+}
+
+// a circle's radius, position and color
+type CircleState = (f32, Vec2, Color);
+
+// one circle entity's journey; circles that only exist in the new layout get entities after
+// the old ones, so a circle's TextureCircle index is its slot
+struct MorphSlot {
+    from: Option<CircleState>,
+    // index into the new layout
+    to: Option<usize>,
+}
+
+#[derive(Component)]
+pub struct Morph {
+    to: RenderToTextureDescriptor,
+    target: AllCircles,
+    slots: Vec<MorphSlot>,
+    from_background: Color,
+    start: f32,
+    duration: f32,
+    easing: Easing,
+}
+
+fn mix_colors(a: Color, b: Color, t: f32) -> Color {
+    let (la, lb) = (
+        Oklab::from_color(Srgb::new(a.r(), a.g(), a.b())),
+        Oklab::from_color(Srgb::new(b.r(), b.g(), b.b())),
+    );
+    let c = Srgb::from_color(la.mix(&lb, t));
+    Color::rgba(c.red, c.green, c.blue, a.a() + (b.a() - a.a()) * t)
+}
+
+fn faded(c: Color, opacity: f32) -> Color {
+    Color::rgba(c.r(), c.g(), c.b(), c.a() * opacity)
+}
+
+impl Morph {
+    // eased progress t seconds into the texture's clock
+    fn progress(&self, t: f32) -> f32 {
+        self.easing
+            .apply((t - self.start) / self.duration.max(f32::EPSILON))
+    }
+
+    // the texture's background t seconds into its clock
+    pub fn background(&self, t: f32) -> Color {
+        mix_colors(
+            self.from_background,
+            self.to.background_color,
+            self.progress(t),
+        )
+    }
+
+    fn finished(&self, t: f32) -> bool {
+        t - self.start >= self.duration
+    }
+
+    fn target_state(&self, index: usize) -> CircleState {
+        (
+            self.target.r[index],
+            self.target.pos[index],
+            self.target.c[index],
+        )
+    }
+
+    // where the circle in slot index is t seconds into the texture's clock
+    pub fn circle(&self, index: usize, t: f32) -> CircleState {
+        let p = self.progress(t);
+        let Some(slot) = self.slots.get(index) else {
+            return (0.0, Vec2::ZERO, Color::NONE);
+        };
+        match (slot.from, slot.to.map(|to| self.target_state(to))) {
+            (Some((r0, p0, c0)), Some((r1, p1, c1))) => {
+                (r0 + (r1 - r0) * p, p0.lerp(p1, p), mix_colors(c0, c1, p))
+            }
+            // fading out where it is
+            (Some((r, pos, c)), None) => (r * (1.0 - p), pos, faded(c, 1.0 - p)),
+            // growing in where it'll be
+            (None, Some((r, pos, c))) => (r * p, pos, faded(c, p)),
+            (None, None) => (0.0, Vec2::ZERO, Color::NONE),
+        }
+    }
+}
+
+// pairs circles of the old layout with circles of the new one; every circle of either ends up
+// in exactly one slot, the old ones first and in their own order
+fn match_circles(from: &AllCircles, to: &AllCircles, matching: MorphMatching) -> Vec<MorphSlot> {
+    let by_size = |all: &AllCircles| {
+        let mut order: Vec<usize> = (0..all.r.len()).collect();
+        order.sort_by(|a, b| all.r[*b].total_cmp(&all.r[*a]));
+        order
+    };
+    let mut pairs: Vec<Option<usize>> = vec![None; from.r.len()];
+    let mut taken = vec![false; to.r.len()];
+    match matching {
+        MorphMatching::Nearest => {
+            for i in by_size(from) {
+                let nearest = (0..to.r.len()).filter(|j| !taken[*j]).min_by(|a, b| {
+                    from.pos[i]
+                        .distance_squared(to.pos[*a])
+                        .total_cmp(&from.pos[i].distance_squared(to.pos[*b]))
+                });
+                if let Some(j) = nearest {
+                    taken[j] = true;
+                    pairs[i] = Some(j);
+                }
+            }
+        }
+        MorphMatching::SizeRank => {
+            for (i, j) in by_size(from).into_iter().zip(by_size(to)) {
+                taken[j] = true;
+                pairs[i] = Some(j);
+            }
+        }
+    }
+    let mut slots: Vec<MorphSlot> = pairs
+        .into_iter()
+        .enumerate()
+        .map(|(i, to)| MorphSlot {
+            from: Some((from.r[i], from.pos[i], from.c[i])),
+            to,
+        })
+        .collect();
+    slots.extend((0..to.r.len()).filter(|j| !taken[*j]).map(|j| MorphSlot {
+        from: None,
+        to: Some(j),
+    }));
+    slots
+}
+
+pub fn start_morphs(
+    mut commands: Commands,
+    mut events: EventReader<MorphTextureEvent>,
+    circle_mesh: Res<CircleMesh>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut query: Query<
+        (
+            Entity,
+            &mut Circles2,
+            &TextureClock,
+            Option<&TexturePalette>,
+        ),
+        Without<Morph>,
+    >,
+) {
+    for e in events.iter() {
+        let Some((owner, mut circles2, clock, palette)) = query
+            .iter_mut()
+            .find(|(_, circles2, _, _)| circles2.name == e.name)
+        else {
+            warn!(
+                "not morphing {}: no Circles2 texture of that name that isn't already morphing",
+                e.name
+            );
+            continue;
+        };
+        if !circles2.is_set_up() {
+            warn!("not morphing {}: it isn't set up yet", e.name);
+            continue;
+        }
+        if e.to.functype != "Circles2" {
+            warn!(
+                "not morphing {} into {}: its functype is {}, not Circles2",
+                e.name, e.to.name, e.to.functype
+            );
+            continue;
+        }
+        // only the palette the texture already has is loaded
+        let palette = palette.filter(|_| e.to.color.palette == circles2.color.palette);
+        if e.to.color.palette.is_some() && palette.is_none() {
+            warn!(
+                "{}: morphing without the palette of {}, which isn't loaded",
+                e.name, e.to.name
+            );
+        }
+        let mut rng = rand::thread_rng();
+        let (target, generator) = pack_circles(
+            &e.to.start_color,
+            e.to.color,
+            palette.map(|p| &p.0),
+            Some(e.to.background_color),
+            circles2.size,
+            &mut rng,
+        );
+        report_adjusted(circles2.name, &generator);
+        let slots = match_circles(circles2.circles(), &target, e.matching);
+
+        let first_pass_layer = RenderLayers::layer(circles2.layer);
+        for (index, slot) in slots.iter().enumerate() {
+            if let (None, Some(to)) = (slot.from, slot.to) {
+                commands
+                    .spawn_bundle(MaterialMesh2dBundle {
+                        mesh: circle_mesh.0.clone().into(),
+                        material: materials.add(ColorMaterial::from(target.c[to])),
+                        transform: Transform::from_translation(target.pos[to].extend(0.0))
+                            .with_scale(Vec3::ZERO),
+                        ..default()
+                    })
+                    .insert(first_pass_layer)
+                    .insert(TextureCircle { owner, index });
+            }
+        }
+        // a reveal still going would fight the morph over the circles' size
+        circles2.reveal = None;
+        commands.entity(owner).insert(Morph {
+            to: e.to,
+            target,
+            slots,
+            from_background: circles2.background_color,
+            start: clock.elapsed(),
+            duration: e.duration,
+            easing: e.easing,
+        });
+    }
+}
+
+// fades the background across, and once a morph is done hands the new layout to the texture
+pub fn update_morphs(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut images: ResMut<Assets<Image>>,
+    mut dyntex: ResMut<DynamicTextures>,
+    mut query: Query<(Entity, &mut Circles2, &mut Morph, &TextureClock)>,
+    mut circles: Query<(Entity, &mut TextureCircle)>,
+    mut cameras: Query<(&mut Camera2d, &RenderLayers), With<RenderToTexturePass>>,
+) {
+    for (owner, mut circles2, mut morph, clock) in &mut query {
+        let t = clock.elapsed();
+        let background = morph.background(t);
+        let layer = RenderLayers::layer(circles2.layer);
+        for (mut camera, layers) in &mut cameras {
+            if *layers == layer {
+                camera.clear_color = ClearColorConfig::Custom(background);
+            }
+        }
+        if !morph.finished(t) {
+            continue;
+        }
+
+        let target = std::mem::take(&mut morph.target);
+        circles2.become_layout(&morph.to, target, t);
+        match morph.to.formulas {
+            Some(path) => {
+                commands
+                    .entity(owner)
+                    .insert(TextureFormulas::new(asset_server.load(path)));
+            }
+            None => {
+                commands.entity(owner).remove::<TextureFormulas>();
+            }
+        }
+        for (entity, mut circle) in &mut circles {
+            if circle.owner != owner {
+                continue;
+            }
+            match morph.slots.get(circle.index).and_then(|slot| slot.to) {
+                Some(to) => circle.index = to,
+                None => commands.entity(entity).despawn(),
+            }
+        }
+
+        // a snapshot for textures that use this one as an input
+        let canvas = circles_canvas(
+            circles2.circles(),
+            circles2.size,
+            circles2.background_color,
+            circles2.filters,
+        );
+        circles2.show_canvas_if_needed(&mut commands, &mut images, &canvas);
+        dyntex.publish_canvas(circles2.name, canvas);
+        dyntex.replace_descriptor(circles2.name, &morph.to);
+        commands.entity(owner).remove::<Morph>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn circles(xs: &[f32], r: &[f32]) -> AllCircles {
+        AllCircles {
+            pos: xs.iter().map(|x| Vec2::new(*x, 0.0)).collect(),
+            r: r.to_vec(),
+            c: vec![Color::WHITE; xs.len()],
+        }
+    }
+
+    fn targets(slots: &[MorphSlot]) -> Vec<Option<usize>> {
+        slots.iter().map(|slot| slot.to).collect()
+    }
+
+    #[test]
+    fn nearest_gives_the_biggest_circles_first_pick() {
+        let from = circles(&[0.0, 10.0], &[1.0, 2.0]);
+        let to = circles(&[1.0, 50.0, 9.0], &[1.0, 1.0, 1.0]);
+        let slots = match_circles(&from, &to, MorphMatching::Nearest);
+        assert_eq!(targets(&slots), vec![Some(0), Some(2), Some(1)]);
+        assert!(slots[..2].iter().all(|slot| slot.from.is_some()));
+        // the circle nobody went to grows in
+        assert!(slots[2].from.is_none());
+
+        // both want the circle at 5; the bigger one gets it
+        let from = circles(&[4.0, 6.0], &[1.0, 3.0]);
+        let to = circles(&[5.0, 100.0], &[1.0, 1.0]);
+        let slots = match_circles(&from, &to, MorphMatching::Nearest);
+        assert_eq!(targets(&slots), vec![Some(1), Some(0)]);
+    }
+
+    #[test]
+    fn size_rank_pairs_circles_by_size() {
+        let from = circles(&[0.0, 1.0], &[1.0, 2.0]);
+        let to = circles(&[0.0, 1.0, 2.0], &[3.0, 1.0, 2.0]);
+        let slots = match_circles(&from, &to, MorphMatching::SizeRank);
+        assert_eq!(targets(&slots), vec![Some(2), Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn circles_without_a_match_fade_out() {
+        let from = circles(&[0.0, 1.0, 2.0], &[1.0, 3.0, 2.0]);
+        let to = circles(&[2.0], &[1.0]);
+        for matching in [MorphMatching::Nearest, MorphMatching::SizeRank] {
+            let slots = match_circles(&from, &to, matching);
+            assert_eq!(targets(&slots), vec![None, Some(0), None], "{matching:?}");
+        }
+    }
+}
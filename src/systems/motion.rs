@@ -0,0 +1,181 @@
+use std::collections::VecDeque;
+
+use bevy::ecs::system::{Local, Res, ResMut};
+use bevy::log::{info, warn};
+use bevy::time::Time;
+use bevy::utils::HashMap;
+
+use crate::systems::canvas::Canvas;
+use crate::systems::dynamic_textures::DynamicTextures;
+
+// the common guideline: no more than three general flashes in any one second
+pub const MAX_SAFE_FLASH_RATE: f32 = 3.0;
+// a change in relative luminance this big, with the darker state below FLASH_DARK_LIMIT, is a flash
+pub const FLASH_LUMINANCE_CHANGE: f32 = 0.1;
+pub const FLASH_DARK_LIMIT: f32 = 0.8;
+
+// how animated textures are allowed to move and change brightness
+pub struct MotionSettings {
+    // caps how fast and how far animations swing, and stops jitter
+    pub reduced_motion: bool,
+    // highest oscillation rate, in full cycles per second, an animation runs at in reduced motion
+    pub max_frequency: f32,
+    // largest lightness swing either side, in reduced motion
+    pub max_lightness_swing: f32,
+}
+
+impl Default for MotionSettings {
+    fn default() -> Self {
+        MotionSettings {
+            reduced_motion: false,
+            // half the flash guideline, since one cycle can brighten and darken once each
+            max_frequency: MAX_SAFE_FLASH_RATE / 2.0,
+            max_lightness_swing: 0.05,
+        }
+    }
+}
+
+impl MotionSettings {
+    // an angular frequency in radians per second, capped in reduced motion
+    pub fn frequency(&self, omega: f32) -> f32 {
+        if self.reduced_motion {
+            omega.min(self.max_frequency * std::f32::consts::TAU)
+        } else {
+            omega
+        }
+    }
+
+    // an amplitude, scaled down in reduced motion so that it's at most max
+    pub fn amplitude(&self, amplitude: f32, max: f32) -> f32 {
+        if self.reduced_motion {
+            amplitude.min(max)
+        } else {
+            amplitude
+        }
+    }
+
+    pub fn lightness_swing(&self, swing: f32) -> f32 {
+        self.amplitude(swing, self.max_lightness_swing)
+    }
+}
+
+pub fn relative_luminance(p: [f32; 4]) -> f32 {
+    let linear = |c: f32| {
+        if c <= 0.040_45 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    0.2126 * linear(p[0]) + 0.7152 * linear(p[1]) + 0.0722 * linear(p[2])
+}
+
+// mean relative luminance of every fourth pixel of every fourth row
+pub fn canvas_luminance(canvas: &Canvas) -> f32 {
+    let mut sum = 0.0;
+    let mut n = 0;
+    for y in (0..canvas.height).step_by(4) {
+        for x in (0..canvas.width).step_by(4) {
+            sum += relative_luminance(canvas.get(x, y));
+            n += 1;
+        }
+    }
+    if n == 0 {
+        0.0
+    } else {
+        sum / n as f32
+    }
+}
+
+// the last second of a texture's overall luminance
+#[derive(Default)]
+pub struct FlashMeter {
+    samples: VecDeque<(f32, f32)>,
+}
+
+impl FlashMeter {
+    pub fn push(&mut self, time: f32, luminance: f32) {
+        self.samples.push_back((time, luminance));
+        while self.samples.front().is_some_and(|(t, _)| *t < time - 1.0) {
+            self.samples.pop_front();
+        }
+    }
+
+    // flashes in the last second: pairs of opposing luminance changes big enough to count
+    pub fn flashes_per_second(&self) -> f32 {
+        let mut transitions = 0;
+        // -1 darkening, 1 brightening, 0 before the first transition
+        let mut direction = 0i8;
+        let Some(&(_, mut extreme)) = self.samples.front() else {
+            return 0.0;
+        };
+        for &(_, l) in &self.samples {
+            let change = l - extreme;
+            let sign = if change > 0.0 { 1 } else { -1 };
+            if change.abs() >= FLASH_LUMINANCE_CHANGE
+                && extreme.min(l) < FLASH_DARK_LIMIT
+                && sign != direction
+            {
+                transitions += 1;
+                direction = sign;
+                extreme = l;
+            } else if change * f32::from(direction) > 0.0 {
+                // still going the same way; measure the next change from the new extreme
+                extreme = l;
+            }
+        }
+        transitions as f32 / 2.0
+    }
+}
+
+// measures how often every animated dynamic texture flashes and reports the ones over the guideline
+#[derive(Default)]
+pub struct FlashAnalyzer {
+    meters: HashMap<String, FlashMeter>,
+    violating: HashMap<String, f32>,
+}
+
+impl FlashAnalyzer {
+    pub fn record(&mut self, name: &str, time: f32, luminance: f32) {
+        let meter = self.meters.entry(name.to_string()).or_default();
+        meter.push(time, luminance);
+        let rate = meter.flashes_per_second();
+        if rate > MAX_SAFE_FLASH_RATE {
+            if self.violating.insert(name.to_string(), rate).is_none() {
+                warn!(
+                    "{} flashes {:.1} times a second, over the limit of {}",
+                    name, rate, MAX_SAFE_FLASH_RATE
+                );
+            }
+        } else if self.violating.remove(name).is_some() {
+            info!("{} is back under the flash limit", name);
+        }
+    }
+
+    pub fn flash_rate(&self, name: &str) -> Option<f32> {
+        self.meters.get(name).map(FlashMeter::flashes_per_second)
+    }
+
+    // textures currently over the limit, with their flash rates
+    pub fn violations(&self) -> impl Iterator<Item = (&str, f32)> {
+        self.violating
+            .iter()
+            .map(|(name, rate)| (name.as_str(), *rate))
+    }
+}
+
+// samples the textures whose pixels are on the CPU, whenever they change
+pub fn measure_canvas_flashes(
+    time: Res<Time>,
+    dyntex: Res<DynamicTextures>,
+    mut analyzer: ResMut<FlashAnalyzer>,
+    mut seen: Local<HashMap<String, u64>>,
+) {
+    let t = time.seconds_since_startup() as f32;
+    for (name, canvas, version) in dyntex.canvases() {
+        if seen.insert(name.to_string(), version) == Some(version) {
+            continue;
+        }
+        analyzer.record(name, t, canvas_luminance(canvas));
+    }
+}
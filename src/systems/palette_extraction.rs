@@ -0,0 +1,247 @@
+use std::fmt::Write;
+
+use bevy::render::color::Color;
+use bevy::render::render_resource::TextureFormat;
+use bevy::render::texture::Image;
+use palette::{FromColor, Hsl, Oklab, Srgb};
+
+use crate::systems::color_palette::{ColorPalette, PaletteEntry};
+use crate::systems::dynamic_textures::StartColor;
+
+// more pixels than this are sampled evenly, which barely changes the result
+const MAX_SAMPLES: usize = 20_000;
+const KMEANS_ITERATIONS: usize = 20;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtractMethod {
+    // median cut, then refined by k-means
+    #[default]
+    KMeans,
+    MedianCut,
+}
+
+impl std::str::FromStr for ExtractMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kmeans" | "k-means" => Ok(ExtractMethod::KMeans),
+            "median-cut" | "mediancut" => Ok(ExtractMethod::MedianCut),
+            _ => Err(format!("unknown extraction method \"{s}\"")),
+        }
+    }
+}
+
+// the dominant colors of an image, heaviest first, and the start color to generate them from
+pub struct ExtractedColors {
+    pub start_color: StartColor,
+    pub palette: ColorPalette,
+}
+
+// the sRGB pixels of an 8-bit RGBA image, or None for other formats
+pub fn image_pixels(image: &Image) -> Option<Vec<[f32; 4]>> {
+    match image.texture_descriptor.format {
+        TextureFormat::Rgba8UnormSrgb | TextureFormat::Rgba8Unorm => {
+            Some(rgba8_pixels(&image.data))
+        }
+        _ => None,
+    }
+}
+
+pub fn rgba8_pixels(data: &[u8]) -> Vec<[f32; 4]> {
+    data.chunks_exact(4)
+        .map(|p| [0, 1, 2, 3].map(|ch| f32::from(p[ch]) / 255.0))
+        .collect()
+}
+
+// up to count colors weighted by how much of the image they cover; mostly transparent pixels
+// are ignored. None if no pixel is opaque enough
+pub fn extract_colors(
+    pixels: &[[f32; 4]],
+    count: usize,
+    method: ExtractMethod,
+) -> Option<ExtractedColors> {
+    let opaque: Vec<&[f32; 4]> = pixels.iter().filter(|p| p[3] >= 0.5).collect();
+    let stride = (opaque.len() / MAX_SAMPLES).max(1);
+    let points: Vec<[f32; 3]> = opaque
+        .iter()
+        .step_by(stride)
+        .map(|p| {
+            let lab = Oklab::from_color(Srgb::new(p[0], p[1], p[2]));
+            [lab.l, lab.a, lab.b]
+        })
+        .collect();
+    if points.is_empty() || count == 0 {
+        return None;
+    }
+
+    let mut clusters = median_cut(&points, count);
+    if method == ExtractMethod::KMeans {
+        clusters = kmeans(&points, clusters);
+    }
+    clusters.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+
+    let total = points.len() as f32;
+    let palette = ColorPalette {
+        name: None,
+        entries: clusters
+            .iter()
+            .map(|(center, size)| {
+                let rgb = Srgb::from_color(Oklab::new(center[0], center[1], center[2]));
+                PaletteEntry {
+                    color: Color::rgb(rgb.red, rgb.green, rgb.blue),
+                    weight: *size as f32 / total,
+                    name: None,
+                }
+            })
+            .collect(),
+    };
+    let heaviest = palette.entries[0].color;
+    let hsl = Hsl::from_color(Srgb::new(heaviest.r(), heaviest.g(), heaviest.b()));
+    Some(ExtractedColors {
+        start_color: StartColor {
+            hue: hsl.hue.to_positive_degrees() / 360.0,
+            saturation: hsl.saturation,
+            lightness: hsl.lightness,
+        },
+        palette,
+    })
+}
+
+fn mean(points: &[[f32; 3]], indices: &[usize]) -> [f32; 3] {
+    let mut sum = [0.0; 3];
+    for i in indices {
+        for ch in 0..3 {
+            sum[ch] += points[*i][ch];
+        }
+    }
+    sum.map(|s| s / indices.len() as f32)
+}
+
+// the widest channel of a box of points and how wide it is
+fn widest_channel(points: &[[f32; 3]], indices: &[usize]) -> (usize, f32) {
+    (0..3)
+        .map(|ch| {
+            let (lo, hi) = indices.iter().fold((f32::MAX, f32::MIN), |(lo, hi), i| {
+                (lo.min(points[*i][ch]), hi.max(points[*i][ch]))
+            });
+            (ch, hi - lo)
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap()
+}
+
+// splits the widest box at its median until there are count boxes; returns their means and sizes
+fn median_cut(points: &[[f32; 3]], count: usize) -> Vec<([f32; 3], usize)> {
+    let mut boxes = vec![(0..points.len()).collect::<Vec<usize>>()];
+    while boxes.len() < count {
+        let Some((widest, (channel, _))) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .map(|(n, b)| (n, widest_channel(points, b)))
+            .max_by(|a, b| a.1 .1.total_cmp(&b.1 .1))
+        else {
+            break;
+        };
+        let mut indices = boxes.swap_remove(widest);
+        indices.sort_by(|a, b| points[*a][channel].total_cmp(&points[*b][channel]));
+        let upper = indices.split_off(indices.len() / 2);
+        boxes.push(indices);
+        boxes.push(upper);
+    }
+    boxes.iter().map(|b| (mean(points, b), b.len())).collect()
+}
+
+fn distance_sq(a: [f32; 3], b: [f32; 3]) -> f32 {
+    (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)
+}
+
+// moves each center to the mean of the points nearest to it, dropping centers no point is nearest to
+fn kmeans(points: &[[f32; 3]], clusters: Vec<([f32; 3], usize)>) -> Vec<([f32; 3], usize)> {
+    let mut centers: Vec<[f32; 3]> = clusters.into_iter().map(|(c, _)| c).collect();
+    let mut members: Vec<Vec<usize>> = Vec::new();
+    for _ in 0..KMEANS_ITERATIONS {
+        members = vec![Vec::new(); centers.len()];
+        for (i, p) in points.iter().enumerate() {
+            let nearest = (0..centers.len())
+                .min_by(|a, b| {
+                    distance_sq(*p, centers[*a]).total_cmp(&distance_sq(*p, centers[*b]))
+                })
+                .unwrap();
+            members[nearest].push(i);
+        }
+        members.retain(|m| !m.is_empty());
+        let moved: Vec<[f32; 3]> = members.iter().map(|m| mean(points, m)).collect();
+        let converged = moved.len() == centers.len()
+            && moved
+                .iter()
+                .zip(&centers)
+                .all(|(a, b)| distance_sq(*a, *b) < 1e-8);
+        centers = moved;
+        if converged {
+            break;
+        }
+    }
+    centers
+        .into_iter()
+        .zip(members.iter().map(Vec::len))
+        .collect()
+}
+
+// the palette as a Lospec-style hex list with a weight column, which ColorPalette::parse_hex reads back
+pub fn to_hex_list(palette: &ColorPalette) -> String {
+    let mut hex = String::new();
+    for e in &palette.entries {
+        let [r, g, b, _] = e.color.as_rgba_f32().map(|c| (c * 255.0).round() as u8);
+        // writing to a String can't fail
+        let _ = writeln!(hex, "{r:02x}{g:02x}{b:02x} {:.4}", e.weight);
+    }
+    hex
+}
+
+// extract-palette <image> [--colors N] [--method kmeans|median-cut] [--out palette.hex]
+// prints a StartColor and the palette, and writes the palette if asked to
+pub fn run_extract_palette_command(args: &[String]) -> Result<(), String> {
+    let usage = "usage: extract-palette <image> [--colors N] [--method kmeans|median-cut] [--out palette.hex]";
+    let mut path = None;
+    let mut count = 8;
+    let mut method = ExtractMethod::default();
+    let mut out = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("{arg} needs a value\n{usage}"))
+        };
+        match arg.as_str() {
+            "--colors" => {
+                count = value()?
+                    .parse()
+                    .map_err(|_| format!("--colors needs a number\n{usage}"))?;
+            }
+            "--method" => method = value()?.parse()?,
+            "--out" => out = Some(value()?.clone()),
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument \"{arg}\"\n{usage}")),
+        }
+    }
+    let path = path.ok_or_else(|| usage.to_string())?;
+
+    let image = image::open(&path).map_err(|e| format!("can't read {path}: {e}"))?;
+    let pixels = rgba8_pixels(image.to_rgba8().as_raw());
+    let extracted = extract_colors(&pixels, count, method)
+        .ok_or_else(|| format!("{path} has no opaque pixels"))?;
+
+    let start = extracted.start_color;
+    println!(
+        "StartColor {{ hue: {:.3}, saturation: {:.3}, lightness: {:.3} }}",
+        start.hue, start.saturation, start.lightness
+    );
+    let hex = to_hex_list(&extracted.palette);
+    print!("{hex}");
+    if let Some(out) = out {
+        std::fs::write(&out, hex).map_err(|e| format!("can't write {out}: {e}"))?;
+    }
+    Ok(())
+}
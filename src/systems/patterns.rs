@@ -0,0 +1,121 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::systems::canvas::Canvas;
+use crate::systems::color_generator::ColorGenerator;
+
+#[derive(Clone, Copy, Debug)]
+pub struct NoiseParams {
+    // size in pixels of the coarsest octave's lattice cells
+    pub scale: f32,
+    pub octaves: u32,
+    // amplitude multiplier from one octave to the next
+    pub persistence: f32,
+    pub seed: u64,
+}
+
+impl Default for NoiseParams {
+    fn default() -> Self {
+        NoiseParams {
+            scale: 64.0,
+            octaves: 4,
+            persistence: 0.5,
+            seed: 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct StippleParams {
+    // dots per 1000 square pixels
+    pub density: f32,
+    pub min_radius: f32,
+    pub max_radius: f32,
+    pub seed: u64,
+}
+
+impl Default for StippleParams {
+    fn default() -> Self {
+        StippleParams {
+            density: 2.0,
+            min_radius: 0.75,
+            max_radius: 2.0,
+            seed: 1,
+        }
+    }
+}
+
+pub fn hash(x: i32, y: i32, seed: u64) -> f32 {
+    let mut h = seed
+        ^ (x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ (y as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xFF51_AFD7_ED55_8CCD);
+    h ^= h >> 33;
+    (h >> 40) as f32 / (1u64 << 24) as f32
+}
+
+fn smoothstep(t: f32) -> f32 {
+    t * t * (3.0 - 2.0 * t)
+}
+
+// smoothly interpolated lattice noise in 0.0..1.0
+pub fn value_noise(x: f32, y: f32, seed: u64) -> f32 {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (smoothstep(x - x0), smoothstep(y - y0));
+    let (ix, iy) = (x0 as i32, y0 as i32);
+    let top = hash(ix, iy, seed) + (hash(ix + 1, iy, seed) - hash(ix, iy, seed)) * tx;
+    let bottom =
+        hash(ix, iy + 1, seed) + (hash(ix + 1, iy + 1, seed) - hash(ix, iy + 1, seed)) * tx;
+    top + (bottom - top) * ty
+}
+
+// several octaves of value noise, normalized back to 0.0..1.0
+pub fn fractal_noise(x: f32, y: f32, params: &NoiseParams) -> f32 {
+    let (mut sum, mut amplitude, mut total, mut frequency) = (0.0, 1.0, 0.0, 1.0);
+    for octave in 0..params.octaves.max(1) {
+        sum += amplitude
+            * value_noise(
+                x * frequency / params.scale,
+                y * frequency / params.scale,
+                params.seed.wrapping_add(u64::from(octave)),
+            );
+        total += amplitude;
+        amplitude *= params.persistence;
+        frequency *= 2.0;
+    }
+    sum / total
+}
+
+pub fn render_noise(params: &NoiseParams, generator: &ColorGenerator, canvas: &mut Canvas) {
+    for y in 0..canvas.height {
+        for x in 0..canvas.width {
+            let t = fractal_noise(x as f32, y as f32, params);
+            canvas.set(x, y, generator.shade(t).as_rgba_f32());
+        }
+    }
+}
+
+// scatters small antialiased dots in shades of the generator's color over a transparent canvas
+pub fn render_stipple(params: &StippleParams, generator: &ColorGenerator, canvas: &mut Canvas) {
+    let mut rng = StdRng::seed_from_u64(params.seed);
+    let dots = (canvas.width * canvas.height) as f32 * params.density / 1000.0;
+    for _ in 0..dots as u32 {
+        let cx = rng.gen::<f32>() * canvas.width as f32;
+        let cy = rng.gen::<f32>() * canvas.height as f32;
+        let r = rng.gen_range(params.min_radius..=params.max_radius.max(params.min_radius));
+        let color = generator.shade(rng.gen()).as_rgba_f32();
+        let x0 = (cx - r).floor().max(0.0) as u32;
+        let x1 = ((cx + r).ceil() as u32).min(canvas.width);
+        let y0 = (cy - r).floor().max(0.0) as u32;
+        let y1 = ((cy + r).ceil() as u32).min(canvas.height);
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let d = ((x as f32 + 0.5 - cx).powi(2) + (y as f32 + 0.5 - cy).powi(2)).sqrt();
+                let coverage = (r - d + 0.5).clamp(0.0, 1.0);
+                if coverage > 0.0 {
+                    canvas.paint(x, y, color, coverage);
+                }
+            }
+        }
+    }
+}
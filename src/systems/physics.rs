@@ -0,0 +1,311 @@
+use bevy::ecs::{event::EventReader, system::Query};
+use bevy::math::Vec2;
+use bevy::utils::HashMap;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::systems::circles::Circles2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PhysicsParams {
+    // world units per second squared
+    pub gravity: Vec2,
+    // pull toward the texture's center, per second squared per world unit away
+    pub attractor: f32,
+    // pull back toward each circle's packed spot, the same way; 0.0 lets circles wander off
+    pub home: f32,
+    // fraction of velocity lost per second
+    pub damping: f32,
+    // 1.0 bounces off walls and other circles without losing speed
+    pub restitution: f32,
+    // circles start moving in random directions at this speed
+    pub initial_speed: f32,
+    pub seed: u64,
+}
+
+impl PhysicsParams {
+    // circles sit still on their spots until something hits them, then wobble back
+    pub const JIGGLE: PhysicsParams = PhysicsParams {
+        gravity: Vec2::ZERO,
+        attractor: 0.0,
+        home: 40.0,
+        damping: 2.0,
+        restitution: 0.6,
+        initial_speed: 0.0,
+        seed: 1,
+    };
+    pub const SNOW_GLOBE: PhysicsParams = PhysicsParams {
+        gravity: Vec2::new(0.0, -200.0),
+        home: 0.0,
+        damping: 0.2,
+        restitution: 0.5,
+        initial_speed: 100.0,
+        ..PhysicsParams::JIGGLE
+    };
+}
+
+impl Default for PhysicsParams {
+    fn default() -> Self {
+        PhysicsParams::JIGGLE
+    }
+}
+
+// circles as rigid bodies inside a square; the same params and steps always give the same result
+pub struct PhysicsWorld {
+    pub params: PhysicsParams,
+    // bodies keep their circles' placement order
+    pub pos: Vec<Vec2>,
+    pub vel: Vec<Vec2>,
+    r: Vec<f32>,
+    home: Vec<Vec2>,
+    // circles packed past the walls stay where they are, and others bounce off them
+    active: Vec<bool>,
+    half_extent: f32,
+    // side of the broad phase grid's cells; no circle is wider
+    cell: f32,
+}
+
+impl PhysicsWorld {
+    pub fn new(pos: &[Vec2], r: &[f32], half_extent: f32, params: PhysicsParams) -> PhysicsWorld {
+        let mut rng = StdRng::seed_from_u64(params.seed);
+        let vel = pos
+            .iter()
+            .map(|_| {
+                let angle = rng.gen::<f32>() * std::f32::consts::TAU;
+                Vec2::new(angle.cos(), angle.sin()) * params.initial_speed
+            })
+            .collect();
+        let active = pos
+            .iter()
+            .zip(r)
+            .map(|(p, r)| p.abs().max_element() + r <= half_extent)
+            .collect();
+        PhysicsWorld {
+            params,
+            pos: pos.to_vec(),
+            vel,
+            r: r.to_vec(),
+            home: pos.to_vec(),
+            active,
+            half_extent,
+            cell: 2.0 * r.iter().copied().fold(1.0, f32::max),
+        }
+    }
+
+    // pushes circles within radius of point away from it, hardest at the point
+    pub fn impulse(&mut self, point: Vec2, radius: f32, speed: f32) {
+        for ((pos, vel), active) in self.pos.iter().zip(self.vel.iter_mut()).zip(&self.active) {
+            if !active {
+                continue;
+            }
+            let away = *pos - point;
+            let d = away.length();
+            if d < radius {
+                *vel += away.normalize_or_zero() * speed * (1.0 - d / radius);
+            }
+        }
+    }
+
+    pub fn step(&mut self, dt: f32) {
+        let p = self.params;
+        let keep = (1.0 - p.damping * dt).max(0.0);
+        for (((pos, vel), home), active) in self
+            .pos
+            .iter_mut()
+            .zip(&mut self.vel)
+            .zip(&self.home)
+            .zip(&self.active)
+        {
+            if !active {
+                continue;
+            }
+            let accel = p.gravity - *pos * p.attractor + (*home - *pos) * p.home;
+            *vel = (*vel + accel * dt) * keep;
+            *pos += *vel * dt;
+        }
+        self.collide();
+        self.bounce_off_walls();
+    }
+
+    fn cell_of(&self, pos: Vec2) -> (i32, i32) {
+        (
+            (pos.x / self.cell).floor() as i32,
+            (pos.y / self.cell).floor() as i32,
+        )
+    }
+
+    // inverse mass; mass goes with area, and circles that stay put can't be pushed
+    fn inverse_mass(&self, i: usize) -> f32 {
+        if self.active[i] {
+            1.0 / (self.r[i] * self.r[i]).max(f32::EPSILON)
+        } else {
+            0.0
+        }
+    }
+
+    fn collide(&mut self) {
+        let mut grid: HashMap<(i32, i32), Vec<usize>> = HashMap::default();
+        for (i, pos) in self.pos.iter().enumerate() {
+            grid.entry(self.cell_of(*pos)).or_default().push(i);
+        }
+        let restitution = self.params.restitution;
+        for i in 0..self.pos.len() {
+            let (cx, cy) = self.cell_of(self.pos[i]);
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let Some(others) = grid.get(&(cx + dx, cy + dy)) else {
+                        continue;
+                    };
+                    for &j in others {
+                        if j <= i {
+                            continue;
+                        }
+                        let d = self.pos[j] - self.pos[i];
+                        let dist = d.length();
+                        let overlap = self.r[i] + self.r[j] - dist;
+                        if overlap <= 0.0 {
+                            continue;
+                        }
+                        let (wi, wj) = (self.inverse_mass(i), self.inverse_mass(j));
+                        if wi + wj == 0.0 {
+                            continue;
+                        }
+                        let normal = if dist > 0.0 { d / dist } else { Vec2::X };
+                        self.pos[i] -= normal * overlap * wi / (wi + wj);
+                        self.pos[j] += normal * overlap * wj / (wi + wj);
+                        let closing = (self.vel[j] - self.vel[i]).dot(normal);
+                        if closing < 0.0 {
+                            let impulse = -(1.0 + restitution) * closing / (wi + wj);
+                            self.vel[i] -= normal * impulse * wi;
+                            self.vel[j] += normal * impulse * wj;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn bounce_off_walls(&mut self) {
+        let restitution = self.params.restitution;
+        for (((pos, vel), r), active) in self
+            .pos
+            .iter_mut()
+            .zip(&mut self.vel)
+            .zip(&self.r)
+            .zip(&self.active)
+        {
+            if !active {
+                continue;
+            }
+            let limit = (self.half_extent - r).max(0.0);
+            for (p, v) in [(&mut pos.x, &mut vel.x), (&mut pos.y, &mut vel.y)] {
+                if p.abs() > limit {
+                    *p = p.clamp(-limit, limit);
+                    if *v * p.signum() > 0.0 {
+                        *v = -*v * restitution;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// knocks the circles of a physics-driven Circles2 texture about, e.g. when the monster is hit
+pub struct TextureImpulseEvent {
+    pub name: String,
+    // in the texture's circle coordinates, centered on the texture
+    pub point: Vec2,
+    pub radius: f32,
+    // world units per second, at the point
+    pub speed: f32,
+}
+
+pub fn apply_texture_impulses(
+    mut events: EventReader<TextureImpulseEvent>,
+    mut query: Query<&mut Circles2>,
+) {
+    for e in events.iter() {
+        for mut circles2 in &mut query {
+            if circles2.name != e.name {
+                continue;
+            }
+            if let Some(world) = circles2.physics_mut() {
+                world.impulse(e.point, e.radius, e.speed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn world(params: PhysicsParams) -> PhysicsWorld {
+        let pos: Vec<Vec2> = (0..20)
+            .map(|i| Vec2::new((i % 5) as f32 * 30.0 - 60.0, (i / 5) as f32 * 30.0 - 45.0))
+            .collect();
+        PhysicsWorld::new(&pos, &[10.0; 20], 100.0, params)
+    }
+
+    #[test]
+    fn the_same_steps_give_the_same_positions() {
+        let (mut a, mut b) = (
+            world(PhysicsParams::SNOW_GLOBE),
+            world(PhysicsParams::SNOW_GLOBE),
+        );
+        for _ in 0..200 {
+            a.step(1.0 / 60.0);
+            b.step(1.0 / 60.0);
+        }
+        assert_eq!(a.pos, b.pos);
+        assert_eq!(a.vel, b.vel);
+        let other_seed = PhysicsParams {
+            seed: 2,
+            ..PhysicsParams::SNOW_GLOBE
+        };
+        let mut c = world(other_seed);
+        for _ in 0..200 {
+            c.step(1.0 / 60.0);
+        }
+        assert_ne!(a.pos, c.pos);
+    }
+
+    #[test]
+    fn circles_stay_inside_the_walls() {
+        let mut w = world(PhysicsParams::SNOW_GLOBE);
+        w.impulse(Vec2::ZERO, f32::INFINITY, 2000.0);
+        for _ in 0..300 {
+            w.step(1.0 / 60.0);
+            assert!(w.pos.iter().all(|p| p.abs().max_element() <= 90.0 + 1e-3));
+        }
+    }
+
+    #[test]
+    fn jiggling_circles_settle_back_home() {
+        let mut w = world(PhysicsParams::JIGGLE);
+        let home = w.pos.clone();
+        w.impulse(Vec2::ZERO, 50.0, 100.0);
+        assert_ne!(w.vel, vec![Vec2::ZERO; 20]);
+        for _ in 0..600 {
+            w.step(1.0 / 60.0);
+        }
+        for (p, h) in w.pos.iter().zip(&home) {
+            assert!(p.distance(*h) < 0.5, "{p} is not back at {h}");
+        }
+    }
+
+    #[test]
+    fn circles_packed_past_the_walls_stay_put() {
+        let pos = [
+            Vec2::new(0.0, 0.0),
+            Vec2::new(150.0, 0.0),
+            Vec2::new(95.0, 0.0),
+        ];
+        let mut w = PhysicsWorld::new(&pos, &[10.0; 3], 100.0, PhysicsParams::SNOW_GLOBE);
+        w.impulse(Vec2::new(120.0, 0.0), f32::INFINITY, 500.0);
+        for _ in 0..60 {
+            w.step(1.0 / 60.0);
+        }
+        assert_eq!(w.pos[1..], pos[1..]);
+        assert_ne!(w.pos[0], pos[0]);
+    }
+}
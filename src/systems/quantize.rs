@@ -0,0 +1,180 @@
+use bevy::render::color::Color;
+use palette::{FromColor, Oklab, Srgb};
+
+use crate::systems::canvas::Canvas;
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dither {
+    // each pixel takes the nearest palette color
+    #[default]
+    None,
+    // ordered dithering with a size x size threshold matrix; size is 2, 4 or 8
+    Bayer {
+        size: u32,
+    },
+    // error diffusion, left to right and top to bottom
+    FloydSteinberg,
+}
+
+// how much a Bayer threshold can push a pixel, relative to the 0.0..=1.0 channel range
+const BAYER_STRENGTH: f32 = 0.25;
+
+fn oklab(c: [f32; 4]) -> [f32; 3] {
+    let lab = Oklab::from_color(Srgb::new(c[0], c[1], c[2]));
+    [lab.l, lab.a, lab.b]
+}
+
+// index of the palette color perceptually closest to c
+fn nearest(palette: &[[f32; 3]], c: [f32; 4]) -> usize {
+    let lab = oklab(c);
+    let distance = |p: &[f32; 3]| (0..3).map(|ch| (p[ch] - lab[ch]).powi(2)).sum::<f32>();
+    (0..palette.len())
+        .min_by(|a, b| distance(&palette[*a]).total_cmp(&distance(&palette[*b])))
+        .unwrap_or(0)
+}
+
+// the threshold at (x, y) of the recursively built Bayer matrix, in -0.5..0.5
+fn bayer_threshold(x: u32, y: u32, size: u32) -> f32 {
+    // the finest bits of x and y are the most significant in the matrix
+    let mut value = 0;
+    let mut bit = 1;
+    while bit < size {
+        let (bx, by) = (u32::from(x & bit != 0), u32::from(y & bit != 0));
+        value = value * 4 + (bx ^ by) * 2 + by;
+        bit *= 2;
+    }
+    (value as f32 + 0.5) / (size * size) as f32 - 0.5
+}
+
+// a palette with its Oklab colors worked out up front, for matching colors one at a time
+pub struct NearestPalette {
+    colors: Vec<Color>,
+    lab: Vec<[f32; 3]>,
+}
+
+impl NearestPalette {
+    pub fn new(palette: &[Color]) -> NearestPalette {
+        NearestPalette {
+            colors: palette.to_vec(),
+            lab: palette.iter().map(|c| oklab(c.as_rgba_f32())).collect(),
+        }
+    }
+
+    // the palette color perceptually closest to color, keeping its alpha
+    pub fn nearest(&self, color: Color) -> Color {
+        if self.colors.is_empty() {
+            return color;
+        }
+        let c = self.colors[nearest(&self.lab, color.as_rgba_f32())];
+        Color::rgba(c.r(), c.g(), c.b(), color.a())
+    }
+}
+
+// replaces every pixel's color by one from palette, keeping alpha, and leaves which one in
+// canvas.palette_indices; palettes past 256 colors only use their first 256
+pub fn quantize(canvas: &mut Canvas, palette: &[Color], dither: Dither) {
+    let palette = &palette[..palette.len().min(256)];
+    if palette.is_empty() {
+        return;
+    }
+    let rgb: Vec<[f32; 4]> = palette.iter().map(|c| c.as_rgba_f32()).collect();
+    let lab: Vec<[f32; 3]> = rgb.iter().map(|c| oklab(*c)).collect();
+    let mut indices = vec![0u8; canvas.pixels.len()];
+    let (w, h) = (canvas.width, canvas.height);
+    for y in 0..h {
+        for x in 0..w {
+            let mut color = canvas.get(x, y);
+            if let Dither::Bayer { size } = dither {
+                let size = size.clamp(2, 8).next_power_of_two();
+                let t = bayer_threshold(x % size, y % size, size) * BAYER_STRENGTH;
+                for ch in color.iter_mut().take(3) {
+                    *ch = (*ch + t).clamp(0.0, 1.0);
+                }
+            }
+            let index = nearest(&lab, color);
+            let q = rgb[index];
+            let alpha = canvas.get(x, y)[3];
+            canvas.set(x, y, [q[0], q[1], q[2], alpha]);
+            indices[(y * w + x) as usize] = index as u8;
+
+            if dither == Dither::FloydSteinberg {
+                let error = [color[0] - q[0], color[1] - q[1], color[2] - q[2]];
+                for (dx, dy, k) in [
+                    (1, 0, 7.0 / 16.0),
+                    (-1, 1, 3.0 / 16.0),
+                    (0, 1, 5.0 / 16.0),
+                    (1, 1, 1.0 / 16.0),
+                ] {
+                    let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                    if nx < 0 || nx >= w as i32 || ny >= h as i32 {
+                        continue;
+                    }
+                    let mut p = canvas.get(nx as u32, ny as u32);
+                    for ch in 0..3 {
+                        p[ch] += error[ch] * k;
+                    }
+                    canvas.set(nx as u32, ny as u32, p);
+                }
+            }
+        }
+    }
+    canvas.palette_indices = Some(indices);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK_AND_WHITE: [Color; 2] = [Color::BLACK, Color::WHITE];
+
+    #[test]
+    fn picks_the_perceptually_nearest_color() {
+        let palette = NearestPalette::new(&[Color::RED, Color::GREEN, Color::BLUE]);
+        assert_eq!(
+            palette.nearest(Color::rgba(0.9, 0.2, 0.1, 0.5)),
+            Color::rgba(1.0, 0.0, 0.0, 0.5)
+        );
+        assert_eq!(palette.nearest(Color::rgb(0.1, 0.2, 0.8)), Color::BLUE);
+        assert_eq!(
+            NearestPalette::new(&[]).nearest(Color::ORANGE),
+            Color::ORANGE
+        );
+    }
+
+    #[test]
+    fn quantizes_every_pixel_and_keeps_alpha() {
+        let mut canvas = Canvas::new(2, 1, [0.1, 0.1, 0.1, 0.4]);
+        canvas.set(1, 0, [0.9, 0.8, 0.9, 1.0]);
+        quantize(&mut canvas, &BLACK_AND_WHITE, Dither::None);
+        assert_eq!(canvas.to_rgba8(), vec![0, 0, 0, 102, 255, 255, 255, 255]);
+        assert_eq!(canvas.palette_indices, Some(vec![0, 1]));
+    }
+
+    #[test]
+    fn dithering_mixes_gray_from_black_and_white() {
+        for dither in [Dither::Bayer { size: 4 }, Dither::FloydSteinberg] {
+            let mut canvas = Canvas::new(8, 8, [0.5, 0.5, 0.5, 1.0]);
+            quantize(&mut canvas, &BLACK_AND_WHITE, dither);
+            let white: usize = canvas
+                .palette_indices
+                .unwrap()
+                .iter()
+                .map(|i| usize::from(*i))
+                .sum();
+            assert!(white > 0 && white < 64, "{dither:?}: {white} white");
+        }
+        let mut canvas = Canvas::new(8, 8, [0.5, 0.5, 0.5, 1.0]);
+        quantize(&mut canvas, &BLACK_AND_WHITE, Dither::None);
+        let indices = canvas.palette_indices.unwrap();
+        assert!(indices.iter().all(|i| *i == indices[0]));
+    }
+
+    #[test]
+    fn the_bayer_matrix_uses_every_threshold_once() {
+        let mut thresholds: Vec<f32> = (0..16).map(|i| bayer_threshold(i % 4, i / 4, 4)).collect();
+        thresholds.sort_by(f32::total_cmp);
+        for (i, t) in thresholds.iter().enumerate() {
+            assert!((t - ((i as f32 + 0.5) / 16.0 - 0.5)).abs() < 1e-6);
+        }
+    }
+}
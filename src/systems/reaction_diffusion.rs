@@ -0,0 +1,341 @@
+use bevy::asset::{Assets, Handle};
+use bevy::ecs::{
+    component::Component,
+    query::Without,
+    system::{Commands, Query, Res, ResMut},
+};
+use bevy::render::{color::Color, texture::Image};
+use bevy::time::Time;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::systems::canvas::{spawn_canvas_sprite, upload_canvas, Canvas};
+use crate::systems::clock::{SimulationProgress, TextureClock};
+use crate::systems::color_generator::{ColorGenerator, ColorOptions};
+use crate::systems::color_palette::{PendingPalette, TexturePalette};
+use crate::systems::dynamic_textures::{
+    DynamicTextures, GeneratorParams, RenderToTextureDescriptor, StartColor,
+};
+use crate::systems::filters::{apply_filters, Filter};
+use crate::systems::motion::{canvas_luminance, FlashAnalyzer};
+
+#[derive(Clone, Copy, Debug)]
+pub struct GrayScottParams {
+    pub feed: f32,
+    pub kill: f32,
+    pub diffuse_u: f32,
+    pub diffuse_v: f32,
+    // simulation steps run before the texture is first shown
+    pub warmup_steps: u32,
+    // simulation steps run every frame when animating
+    pub steps_per_frame: u32,
+    pub seed: u64,
+    pub animate: bool,
+}
+
+impl GrayScottParams {
+    pub const CORAL: GrayScottParams = GrayScottParams {
+        feed: 0.0545,
+        kill: 0.062,
+        diffuse_u: 1.0,
+        diffuse_v: 0.5,
+        warmup_steps: 2000,
+        steps_per_frame: 8,
+        seed: 1,
+        animate: true,
+    };
+    pub const MITOSIS: GrayScottParams = GrayScottParams {
+        feed: 0.0367,
+        kill: 0.0649,
+        ..GrayScottParams::CORAL
+    };
+    pub const SPOTS: GrayScottParams = GrayScottParams {
+        feed: 0.035,
+        kill: 0.065,
+        ..GrayScottParams::CORAL
+    };
+}
+
+impl Default for GrayScottParams {
+    fn default() -> Self {
+        GrayScottParams::CORAL
+    }
+}
+
+// Gray-Scott reaction-diffusion on a square, wrapping grid
+#[derive(Clone)]
+pub struct GrayScott {
+    size: usize,
+    params: GrayScottParams,
+    u: Vec<f32>,
+    v: Vec<f32>,
+    next_u: Vec<f32>,
+    next_v: Vec<f32>,
+}
+
+impl GrayScott {
+    pub fn new(size: u32, params: GrayScottParams) -> GrayScott {
+        let size = size as usize;
+        let mut u = vec![1.0; size * size];
+        let mut v = vec![0.0; size * size];
+
+        // seed a handful of square patches of V to start the reaction
+        let mut rng = StdRng::seed_from_u64(params.seed);
+        let patch = (size / 16).max(2);
+        let patches = (size * size / 2048).max(4);
+        for _ in 0..patches {
+            let px = rng.gen_range(0..size);
+            let py = rng.gen_range(0..size);
+            for dy in 0..patch {
+                for dx in 0..patch {
+                    let i = ((py + dy) % size) * size + (px + dx) % size;
+                    u[i] = 0.5;
+                    v[i] = 0.25 + rng.gen::<f32>() * 0.05;
+                }
+            }
+        }
+
+        GrayScott {
+            size,
+            params,
+            next_u: u.clone(),
+            next_v: v.clone(),
+            u,
+            v,
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size as u32
+    }
+
+    pub fn u(&self) -> &[f32] {
+        &self.u
+    }
+
+    pub fn v(&self) -> &[f32] {
+        &self.v
+    }
+
+    pub fn step(&mut self) {
+        let n = self.size;
+        let GrayScottParams {
+            feed,
+            kill,
+            diffuse_u,
+            diffuse_v,
+            ..
+        } = self.params;
+        for y in 0..n {
+            let up = (y + n - 1) % n * n;
+            let row = y * n;
+            let down = (y + 1) % n * n;
+            for x in 0..n {
+                let left = (x + n - 1) % n;
+                let right = (x + 1) % n;
+                let i = row + x;
+                // 3x3 laplacian: 0.2 for edge neighbours, 0.05 for corners
+                let lap = |f: &[f32]| {
+                    0.2 * (f[up + x] + f[down + x] + f[row + left] + f[row + right])
+                        + 0.05 * (f[up + left] + f[up + right] + f[down + left] + f[down + right])
+                        - f[i]
+                };
+                let (u, v) = (self.u[i], self.v[i]);
+                let uvv = u * v * v;
+                let nu = u + diffuse_u * lap(&self.u) - uvv + feed * (1.0 - u);
+                let nv = v + diffuse_v * lap(&self.v) + uvv - (kill + feed) * v;
+                self.next_u[i] = nu.clamp(0.0, 1.0);
+                self.next_v[i] = nv.clamp(0.0, 1.0);
+            }
+        }
+        std::mem::swap(&mut self.u, &mut self.next_u);
+        std::mem::swap(&mut self.v, &mut self.next_v);
+    }
+
+    pub fn run(&mut self, steps: u32) {
+        for _ in 0..steps {
+            self.step();
+        }
+    }
+
+    // colors each cell by its V concentration, fading to the background where there is none
+    pub fn render(&self, generator: &ColorGenerator, background_color: Color, canvas: &mut Canvas) {
+        let bg = background_color.as_rgba_f32();
+        for (p, v) in canvas.pixels.iter_mut().zip(&self.v) {
+            let t = (*v * 4.0).clamp(0.0, 1.0);
+            let c = generator.shade(t).as_rgba_f32();
+            for ch in 0..4 {
+                p[ch] = bg[ch] + (c[ch] - bg[ch]) * t;
+            }
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct ReactionDiffusion {
+    pub name: &'static str,
+    pub layer: u8,
+    pub size: u32,
+    pub start_color: StartColor,
+    pub background_color: Color,
+    pub color: ColorOptions,
+    pub params: GrayScottParams,
+    pub filters: &'static [Filter],
+    generator: Option<ColorGenerator>,
+    sim: Option<GrayScott>,
+    // the simulation as it was set up, to start over from when the clock seeks back
+    initial: Option<GrayScott>,
+    // from the clock time it was set up at
+    progress: SimulationProgress,
+    canvas: Option<Canvas>,
+    image: Handle<Image>,
+    done_setup: bool,
+}
+
+impl ReactionDiffusion {
+    pub fn new(layer: u8, desc: &RenderToTextureDescriptor) -> ReactionDiffusion {
+        let params = match desc.params {
+            GeneratorParams::GrayScott(params) => params,
+            _ => GrayScottParams::default(),
+        };
+        ReactionDiffusion {
+            name: desc.name,
+            layer,
+            size: desc.size,
+            start_color: desc.start_color,
+            background_color: desc.background_color,
+            color: desc.color,
+            params,
+            filters: desc.filters,
+            generator: None,
+            sim: None,
+            initial: None,
+            progress: SimulationProgress::default(),
+            canvas: None,
+            image: Handle::default(),
+            done_setup: false,
+        }
+    }
+}
+
+pub fn reaction_diffusion_setup(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut dyntex: ResMut<DynamicTextures>,
+    mut query: Query<
+        (
+            &mut ReactionDiffusion,
+            &TextureClock,
+            Option<&TexturePalette>,
+        ),
+        Without<PendingPalette>,
+    >,
+) {
+    for (mut rd, clock, palette) in &mut query {
+        if rd.done_setup {
+            continue;
+        }
+        let (generator, _) =
+            ColorGenerator::from_start_color(&rd.start_color, rd.color, palette.map(|p| &p.0));
+        let mut sim = GrayScott::new(rd.size, rd.params);
+        sim.run(rd.params.warmup_steps);
+
+        let mut canvas = Canvas::new(rd.size, rd.size, rd.background_color.as_rgba_f32());
+        sim.render(&generator, rd.background_color, &mut canvas);
+        apply_filters(&mut canvas, rd.filters);
+        rd.image = spawn_canvas_sprite(&mut commands, &mut images, &canvas, rd.layer);
+        dyntex.publish_canvas(rd.name, canvas.clone());
+        rd.generator = Some(generator);
+        rd.initial = Some(sim.clone());
+        rd.sim = Some(sim);
+        rd.progress = SimulationProgress::new(clock.elapsed());
+        rd.canvas = Some(canvas);
+        rd.done_setup = true;
+    }
+}
+
+pub fn reaction_diffusion_update(
+    time: Res<Time>,
+    mut images: ResMut<Assets<Image>>,
+    mut dyntex: ResMut<DynamicTextures>,
+    mut flashes: ResMut<FlashAnalyzer>,
+    mut query: Query<(&mut ReactionDiffusion, &TextureClock)>,
+) {
+    for (mut rd, clock) in &mut query {
+        if !rd.done_setup || !rd.params.animate {
+            continue;
+        }
+        let (restart, frames) = rd.progress.advance(clock);
+        if frames == 0 && !restart {
+            continue;
+        }
+        let steps = rd.params.steps_per_frame * frames;
+        let background_color = rd.background_color;
+        let rd = &mut *rd;
+        if restart {
+            rd.sim = rd.initial.clone();
+        }
+        if let (Some(generator), Some(sim), Some(canvas)) =
+            (rd.generator.as_ref(), rd.sim.as_mut(), rd.canvas.as_mut())
+        {
+            sim.run(steps);
+            sim.render(generator, background_color, canvas);
+            apply_filters(canvas, rd.filters);
+            upload_canvas(&mut images, &rd.image, canvas);
+            if dyntex.wants_canvas(rd.name, rd.filters) {
+                // measure_canvas_flashes samples it from there
+                dyntex.publish_canvas(rd.name, canvas.clone());
+            } else {
+                flashes.record(
+                    rd.name,
+                    time.seconds_since_startup() as f32,
+                    canvas_luminance(canvas),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn without_v_nothing_reacts() {
+        let mut gs = GrayScott::new(16, GrayScottParams::default());
+        gs.u.fill(1.0);
+        gs.v.fill(0.0);
+        gs.run(10);
+        assert!(gs.u().iter().all(|u| (u - 1.0).abs() < 1e-6));
+        assert!(gs.v().iter().all(|v| v.abs() < 1e-6));
+    }
+
+    #[test]
+    fn the_same_seed_grows_the_same_pattern() {
+        let mut a = GrayScott::new(32, GrayScottParams::CORAL);
+        let mut b = a.clone();
+        a.run(50);
+        b.run(50);
+        assert_eq!(a.u(), b.u());
+        assert_eq!(a.v(), b.v());
+        assert!(a.v().iter().all(|v| (0.0..=1.0).contains(v)));
+        assert_ne!(a.v(), GrayScott::new(32, GrayScottParams::CORAL).v());
+    }
+
+    #[test]
+    fn diffusion_wraps_around_the_edges() {
+        let params = GrayScottParams {
+            feed: 0.0,
+            kill: 0.0,
+            ..GrayScottParams::default()
+        };
+        let mut gs = GrayScott::new(8, params);
+        gs.v.fill(0.0);
+        gs.u.fill(0.0);
+        gs.u[0] = 1.0;
+        gs.step();
+        // the corner's neighbours across both edges get some of it
+        assert!(gs.u()[7] > 0.0);
+        assert!(gs.u()[7 * 8] > 0.0);
+        assert!(gs.u()[7 * 8 + 7] > 0.0);
+    }
+}
@@ -0,0 +1,254 @@
+use bevy::ecs::{
+    event::EventReader,
+    system::{Query, Res},
+};
+use bevy::math::Vec2;
+use bevy::render::color::Color;
+use bevy::time::Time;
+use palette::{FromColor, Hsl, Srgb};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::systems::circles::Circles2;
+use crate::systems::motion::MotionSettings;
+
+// how much of a hit's flash is left after a second
+const FLASH_DECAY: f32 = 6.0;
+const ENRAGE_DECAY: f32 = 0.5;
+// circles fade out and back in at this fraction of full size per second
+const WEAR_SPEED: f32 = 4.0;
+// fraction of the circles a full intensity hit knocks out
+const WEAR_PER_HIT: f32 = 0.05;
+// world units per second a full intensity hit knocks physics-driven circles about with
+const HIT_SPEED: f32 = 300.0;
+// the most a flash lightens circles in reduced motion
+const REDUCED_FLASH: f32 = 0.2;
+// flash and enrage below this are over
+const SETTLED: f32 = 1e-3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReactionKind {
+    // a white flash, and some circles knocked out for good
+    Hit,
+    // knocked out circles grow back
+    Heal,
+    // the colors swing toward red and slowly calm down
+    Enrage,
+}
+
+// gameplay telling a Circles2 texture what just happened to its monster
+pub struct TextureReact {
+    pub name: String,
+    pub kind: ReactionKind,
+    // 0.0..=1.0
+    pub intensity: f32,
+}
+
+// the transient and lasting effects reactions have left on a texture's circles; they decay in
+// real time rather than on the texture's clock, so they play out while it's paused or slowed
+pub struct Reactions {
+    flash: f32,
+    enrage: f32,
+    worn: Vec<bool>,
+    // how much of each circle is showing, easing toward worn
+    presence: Vec<f32>,
+    // whether anything changed since the last tick, so the circles need drawing again
+    changed: bool,
+    rng: StdRng,
+}
+
+impl Reactions {
+    pub fn new(count: usize) -> Reactions {
+        Reactions {
+            flash: 0.0,
+            enrage: 0.0,
+            worn: vec![false; count],
+            presence: vec![1.0; count],
+            changed: false,
+            // the same hits always wear the same circles
+            rng: StdRng::seed_from_u64(1),
+        }
+    }
+
+    pub fn react(&mut self, kind: ReactionKind, intensity: f32) {
+        let intensity = intensity.clamp(0.0, 1.0);
+        self.changed = true;
+        match kind {
+            ReactionKind::Hit => {
+                self.flash = self.flash.max(intensity);
+                let knocked = (self.worn.len() as f32 * WEAR_PER_HIT * intensity).ceil() as usize;
+                self.set_worn(knocked, true);
+            }
+            ReactionKind::Heal => {
+                let restored = (self.worn.len() as f32 * WEAR_PER_HIT * intensity).ceil() as usize;
+                self.set_worn(restored, false);
+            }
+            ReactionKind::Enrage => self.enrage = (self.enrage + intensity).min(1.0),
+        }
+    }
+
+    // flips up to count random circles that aren't already worn to worn, or back
+    fn set_worn(&mut self, count: usize, worn: bool) {
+        let mut candidates: Vec<usize> = (0..self.worn.len())
+            .filter(|i| self.worn[*i] != worn)
+            .collect();
+        for _ in 0..count.min(candidates.len()) {
+            let i = candidates.swap_remove(self.rng.gen_range(0..candidates.len()));
+            self.worn[i] = worn;
+        }
+    }
+
+    // fraction of circles worn away
+    pub fn wear(&self) -> f32 {
+        self.worn.iter().filter(|w| **w).count() as f32 / self.worn.len().max(1) as f32
+    }
+
+    pub fn tick(&mut self, dt: f32) {
+        // the tick that settles a reaction still changes the circles
+        self.changed = self.flash > 0.0
+            || self.enrage > 0.0
+            || self
+                .presence
+                .iter()
+                .zip(&self.worn)
+                .any(|(presence, worn)| {
+                    (*presence - if *worn { 0.0 } else { 1.0 }).abs() > f32::EPSILON
+                });
+        self.flash *= (-FLASH_DECAY * dt).exp();
+        self.enrage *= (-ENRAGE_DECAY * dt).exp();
+        if self.flash < SETTLED {
+            self.flash = 0.0;
+        }
+        if self.enrage < SETTLED {
+            self.enrage = 0.0;
+        }
+        for (presence, worn) in self.presence.iter_mut().zip(&self.worn) {
+            let target = if *worn { 0.0 } else { 1.0 };
+            *presence += (target - *presence).clamp(-WEAR_SPEED * dt, WEAR_SPEED * dt);
+        }
+    }
+
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+
+    pub fn presence(&self, index: usize) -> f32 {
+        self.presence.get(index).copied().unwrap_or(1.0)
+    }
+
+    // color with the flash and enrage applied
+    pub fn tint(&self, color: Color, motion: &MotionSettings) -> Color {
+        if self.flash <= 0.0 && self.enrage <= 0.0 {
+            return color;
+        }
+        let mut hsl = Hsl::from_color(Srgb::new(color.r(), color.g(), color.b()));
+        // the short way round to red
+        let hue = hsl.hue.to_degrees();
+        hsl.hue -= hue * self.enrage;
+        hsl.saturation += (1.0 - hsl.saturation) * self.enrage * 0.5;
+        let c = Srgb::from_color(hsl);
+        let flash = motion.amplitude(self.flash, REDUCED_FLASH);
+        Color::rgba(
+            c.red + (1.0 - c.red) * flash,
+            c.green + (1.0 - c.green) * flash,
+            c.blue + (1.0 - c.blue) * flash,
+            color.a(),
+        )
+    }
+}
+
+pub fn react_to_events(mut events: EventReader<TextureReact>, mut query: Query<&mut Circles2>) {
+    for e in events.iter() {
+        for mut circles2 in &mut query {
+            if circles2.name != e.name {
+                continue;
+            }
+            circles2.reactions_mut().react(e.kind, e.intensity);
+            if e.kind == ReactionKind::Hit {
+                if let Some(world) = circles2.physics_mut() {
+                    world.impulse(Vec2::ZERO, f32::INFINITY, HIT_SPEED * e.intensity);
+                }
+            }
+        }
+    }
+}
+
+pub fn decay_reactions(time: Res<Time>, mut query: Query<&mut Circles2>) {
+    for mut circles2 in &mut query {
+        circles2.reactions_mut().tick(time.delta_seconds());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hits_flash_and_wear_circles_that_heals_restore() {
+        let mut reactions = Reactions::new(40);
+        reactions.react(ReactionKind::Hit, 1.0);
+        assert!((reactions.flash - 1.0).abs() < f32::EPSILON);
+        assert!((reactions.wear() - 0.05).abs() < 1e-6);
+        reactions.react(ReactionKind::Hit, 0.5);
+        // a weaker hit doesn't dim the flash, but wears a circle more
+        assert!((reactions.flash - 1.0).abs() < f32::EPSILON);
+        assert!((reactions.wear() - 0.075).abs() < 1e-6);
+        reactions.react(ReactionKind::Heal, 1.0);
+        assert!((reactions.wear() - 0.025).abs() < 1e-6);
+        reactions.react(ReactionKind::Heal, 1.0);
+        assert!(reactions.wear() < f32::EPSILON);
+    }
+
+    #[test]
+    fn the_same_hits_wear_the_same_circles() {
+        let (mut a, mut b) = (Reactions::new(100), Reactions::new(100));
+        for _ in 0..3 {
+            a.react(ReactionKind::Hit, 0.7);
+            b.react(ReactionKind::Hit, 0.7);
+        }
+        assert_eq!(a.worn, b.worn);
+    }
+
+    #[test]
+    fn enrage_builds_up_and_everything_calms_down() {
+        let mut reactions = Reactions::new(20);
+        reactions.react(ReactionKind::Enrage, 0.7);
+        reactions.react(ReactionKind::Enrage, 0.7);
+        assert!((reactions.enrage - 1.0).abs() < f32::EPSILON);
+        reactions.react(ReactionKind::Hit, 1.0);
+        assert!(reactions.changed());
+        for _ in 0..20 {
+            reactions.tick(1.0);
+        }
+        assert!(reactions.flash < f32::EPSILON && reactions.enrage < f32::EPSILON);
+        // the worn circles have faded out for good, and the rest are all there
+        for (i, worn) in reactions.worn.iter().enumerate() {
+            let expected = if *worn { 0.0 } else { 1.0 };
+            assert!((reactions.presence(i) - expected).abs() < f32::EPSILON);
+        }
+        // nothing left to redraw for
+        reactions.tick(1.0);
+        assert!(!reactions.changed());
+    }
+
+    #[test]
+    fn worn_circles_fade_at_wear_speed() {
+        let mut reactions = Reactions::new(1);
+        reactions.react(ReactionKind::Hit, 1.0);
+        reactions.tick(0.125);
+        assert!((reactions.presence(0) - 0.5).abs() < 1e-6);
+        reactions.react(ReactionKind::Heal, 1.0);
+        reactions.tick(0.0625);
+        assert!((reactions.presence(0) - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn enraged_colors_swing_toward_red() {
+        let motion = MotionSettings::default();
+        let mut reactions = Reactions::new(0);
+        let blue = Color::rgb(0.2, 0.3, 0.9);
+        assert_eq!(reactions.tint(blue, &motion), blue);
+        reactions.react(ReactionKind::Enrage, 1.0);
+        let tinted = reactions.tint(blue, &motion);
+        assert!(tinted.r() > tinted.b(), "{tinted:?}");
+    }
+}
@@ -0,0 +1,343 @@
+use bevy::asset::{Assets, Handle};
+use bevy::ecs::{
+    component::Component,
+    query::Without,
+    system::{Commands, Query, ResMut},
+};
+use bevy::log::error;
+use bevy::render::texture::Image;
+use bevy::utils::HashMap;
+
+use crate::systems::canvas::{spawn_canvas_sprite, upload_canvas, Canvas};
+use crate::systems::color_palette::{PendingPalette, TexturePalette};
+use crate::systems::dynamic_textures::{DynamicTextures, RenderToTextureDescriptor};
+use crate::systems::filters::apply_filters;
+use crate::systems::layers::{composite, render_generator, render_layers, BlendMode};
+
+#[derive(Clone, Copy, Debug)]
+pub enum InputUsage {
+    // multiplies the texture's alpha by the input's luminance
+    Mask,
+    // maps the texture's luminance through the input's middle row, used as a gradient
+    ColorSample,
+    // offsets where the texture is sampled by the input's red and green channels, up to strength pixels
+    Displace { strength: f32 },
+    // draws the input over the texture
+    Composite { blend: BlendMode, opacity: f32 },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TextureInput {
+    // name of another DynamicTextures entry
+    pub name: &'static str,
+    pub usage: InputUsage,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GraphError {
+    // the names along the cycle, starting and ending with the same texture
+    Cycle(Vec<String>),
+}
+
+impl std::fmt::Display for GraphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GraphError::Cycle(names) => {
+                write!(f, "texture inputs form a cycle: {}", names.join(" -> "))
+            }
+        }
+    }
+}
+
+// which dynamic textures are inputs of which
+#[derive(Default)]
+pub struct TextureGraph {
+    inputs: HashMap<String, Vec<String>>,
+}
+
+impl TextureGraph {
+    // adds a texture, refusing it if its inputs would lead back to it; inputs don't have to exist yet
+    pub fn add_node(&mut self, name: &str, inputs: &[&str]) -> Result<(), GraphError> {
+        let previous = self.inputs.insert(
+            name.to_string(),
+            inputs.iter().map(|input| (*input).to_string()).collect(),
+        );
+        if let Some(cycle) = self.find_cycle_from(name) {
+            match previous {
+                Some(previous) => self.inputs.insert(name.to_string(), previous),
+                None => self.inputs.remove(name),
+            };
+            return Err(GraphError::Cycle(cycle));
+        }
+        Ok(())
+    }
+
+    pub fn inputs_of(&self, name: &str) -> &[String] {
+        self.inputs.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn has_dependents(&self, name: &str) -> bool {
+        self.inputs
+            .values()
+            .any(|inputs| inputs.iter().any(|i| i == name))
+    }
+
+    // every texture, each one after all of its inputs
+    pub fn topological_order(&self) -> Vec<String> {
+        let mut order = Vec::with_capacity(self.inputs.len());
+        let mut visited: HashMap<&str, ()> = HashMap::default();
+        let mut names: Vec<&String> = self.inputs.keys().collect();
+        names.sort();
+        for name in names {
+            self.visit(name, &mut visited, &mut order);
+        }
+        order
+    }
+
+    fn visit<'a>(
+        &'a self,
+        name: &'a str,
+        visited: &mut HashMap<&'a str, ()>,
+        order: &mut Vec<String>,
+    ) {
+        if visited.insert(name, ()).is_some() {
+            return;
+        }
+        for input in self.inputs_of(name) {
+            self.visit(input, visited, order);
+        }
+        if self.inputs.contains_key(name) {
+            order.push(name.to_string());
+        }
+    }
+
+    fn find_cycle_from(&self, start: &str) -> Option<Vec<String>> {
+        let mut path = vec![start.to_string()];
+        if self.walk_to(start, start, &mut path) {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    // depth-first search for target along inputs, leaving the path taken in path
+    fn walk_to(&self, from: &str, target: &str, path: &mut Vec<String>) -> bool {
+        for input in self.inputs_of(from) {
+            if path.iter().skip(1).any(|p| p == input) {
+                continue;
+            }
+            path.push(input.clone());
+            if input == target || self.walk_to(input, target, path) {
+                return true;
+            }
+            path.pop();
+        }
+        false
+    }
+}
+
+fn luminance(p: [f32; 4]) -> f32 {
+    0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2]
+}
+
+// looks up a pixel at the same relative position, so inputs of a different size still line up
+fn sample(canvas: &Canvas, u: f32, v: f32) -> [f32; 4] {
+    let x = ((u * canvas.width as f32) as u32).min(canvas.width - 1);
+    let y = ((v * canvas.height as f32) as u32).min(canvas.height - 1);
+    canvas.get(x, y)
+}
+
+pub fn apply_input(canvas: &mut Canvas, input: &Canvas, usage: InputUsage) {
+    let (w, h) = (canvas.width as f32, canvas.height as f32);
+    match usage {
+        InputUsage::Mask => {
+            for y in 0..canvas.height {
+                for x in 0..canvas.width {
+                    let m = sample(input, x as f32 / w, y as f32 / h);
+                    let mut p = canvas.get(x, y);
+                    p[3] *= luminance(m) * m[3];
+                    canvas.set(x, y, p);
+                }
+            }
+        }
+        InputUsage::ColorSample => {
+            for p in &mut canvas.pixels {
+                let c = sample(input, luminance(*p).clamp(0.0, 1.0), 0.5);
+                *p = [c[0], c[1], c[2], p[3]];
+            }
+        }
+        InputUsage::Displace { strength } => {
+            let source = canvas.clone();
+            for y in 0..canvas.height {
+                for x in 0..canvas.width {
+                    let d = sample(input, x as f32 / w, y as f32 / h);
+                    let sx = (x as f32 + (d[0] - 0.5) * 2.0 * strength).clamp(0.0, w - 1.0);
+                    let sy = (y as f32 + (d[1] - 0.5) * 2.0 * strength).clamp(0.0, h - 1.0);
+                    canvas.set(x, y, source.get(sx as u32, sy as u32));
+                }
+            }
+        }
+        InputUsage::Composite { blend, opacity } => {
+            if input.width == canvas.width && input.height == canvas.height {
+                composite(canvas, input, blend, opacity);
+            } else {
+                let mut resized = Canvas::new(canvas.width, canvas.height, [0.0; 4]);
+                for y in 0..canvas.height {
+                    for x in 0..canvas.width {
+                        resized.set(x, y, sample(input, x as f32 / w, y as f32 / h));
+                    }
+                }
+                composite(canvas, &resized, blend, opacity);
+            }
+        }
+    }
+}
+
+// a dynamic texture built from its own generator plus other dynamic textures
+#[derive(Component)]
+pub struct GraphNode {
+    pub layer: u8,
+    pub descriptor: RenderToTextureDescriptor,
+    base: Option<Canvas>,
+    // versions of the inputs the current image was made from
+    seen: Vec<u64>,
+    image: Handle<Image>,
+}
+
+impl GraphNode {
+    pub fn new(layer: u8, desc: &RenderToTextureDescriptor) -> GraphNode {
+        GraphNode {
+            layer,
+            descriptor: *desc,
+            base: None,
+            seen: Vec::new(),
+            image: Handle::default(),
+        }
+    }
+}
+
+// regenerates, in dependency order, every graph node whose inputs have changed
+pub fn texture_graph_update(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut dyntex: ResMut<DynamicTextures>,
+    mut query: Query<(&mut GraphNode, Option<&TexturePalette>), Without<PendingPalette>>,
+) {
+    if query.is_empty() {
+        return;
+    }
+    let order = dyntex.graph().topological_order();
+    let mut nodes: Vec<_> = query.iter_mut().collect();
+    nodes.sort_by_key(|(node, _)| order.iter().position(|name| name == node.descriptor.name));
+
+    for (node, palette) in &mut nodes {
+        let palette = palette.map(|p| &p.0);
+        let desc = node.descriptor;
+        let versions: Option<Vec<u64>> = desc
+            .inputs
+            .iter()
+            .map(|input| dyntex.canvas_version(input.name))
+            .collect();
+        // wait until every input has been generated
+        let Some(versions) = versions else {
+            continue;
+        };
+        if node.base.is_some() && versions == node.seen {
+            continue;
+        }
+
+        if node.base.is_none() {
+            node.base = Some(if desc.functype == "Layered" {
+                render_layers(&desc, palette)
+            } else {
+                // over the background, the way render_layers draws its layers
+                let mut base =
+                    Canvas::new(desc.size, desc.size, desc.background_color.as_rgba_f32());
+                if let Some(rendered) = render_generator(
+                    desc.functype,
+                    desc.params,
+                    &desc.start_color,
+                    desc.color,
+                    palette,
+                    desc.size,
+                ) {
+                    composite(&mut base, &rendered, BlendMode::Normal, 1.0);
+                } else {
+                    error!("can't generate {} for texture {}", desc.functype, desc.name);
+                }
+                base
+            });
+        }
+        let mut canvas = node.base.clone().unwrap();
+        for input in desc.inputs {
+            if let Some(input_canvas) = dyntex.canvas(input.name) {
+                apply_input(&mut canvas, input_canvas, input.usage);
+            }
+        }
+        apply_filters(&mut canvas, desc.filters);
+
+        if node.seen.is_empty() {
+            node.image = spawn_canvas_sprite(&mut commands, &mut images, &canvas, node.layer);
+        } else {
+            upload_canvas(&mut images, &node.image, &canvas);
+        }
+        node.seen = versions;
+        dyntex.publish_canvas(desc.name, canvas);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_inputs_before_the_textures_using_them() {
+        let mut graph = TextureGraph::default();
+        graph.add_node("composite", &["noise", "circles"]).unwrap();
+        graph.add_node("circles", &["noise"]).unwrap();
+        graph.add_node("noise", &[]).unwrap();
+        assert_eq!(
+            graph.topological_order(),
+            vec!["noise", "circles", "composite"]
+        );
+        assert!(graph.has_dependents("noise"));
+        assert!(!graph.has_dependents("composite"));
+    }
+
+    #[test]
+    fn leaves_out_inputs_that_were_never_added() {
+        let mut graph = TextureGraph::default();
+        graph.add_node("a", &["missing"]).unwrap();
+        assert_eq!(graph.topological_order(), vec!["a"]);
+    }
+
+    #[test]
+    fn refuses_a_node_that_closes_a_cycle() {
+        let mut graph = TextureGraph::default();
+        graph.add_node("a", &["b"]).unwrap();
+        graph.add_node("b", &["c"]).unwrap();
+        assert_eq!(
+            graph.add_node("c", &["a"]),
+            Err(GraphError::Cycle(vec![
+                "c".to_string(),
+                "a".to_string(),
+                "b".to_string(),
+                "c".to_string()
+            ]))
+        );
+        // the graph is left as it was
+        assert!(graph.inputs_of("c").is_empty());
+        assert_eq!(graph.topological_order(), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn a_refused_replacement_keeps_the_old_inputs() {
+        let mut graph = TextureGraph::default();
+        graph.add_node("a", &["b"]).unwrap();
+        graph.add_node("b", &[]).unwrap();
+        assert!(graph.add_node("b", &["a"]).is_err());
+        assert!(graph.add_node("a", &["a"]).is_err());
+        assert_eq!(graph.inputs_of("a"), ["b".to_string()]);
+        assert!(graph.inputs_of("b").is_empty());
+    }
+}